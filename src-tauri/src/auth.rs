@@ -1,24 +1,84 @@
-use reqwest::Client;
+use crate::credentials::{self, CredentialBackend};
+use crate::error::{AppError, ErrorKind};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use std::sync::OnceLock;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
 
-/// Global path to the credentials file, set once at app startup
-static AUTH_FILE_PATH: OnceLock<PathBuf> = OnceLock::new();
+/// Where the session is persisted, chosen once at app startup
+static BACKEND: OnceLock<Box<dyn CredentialBackend>> = OnceLock::new();
 
-const AUTH_FILE_NAME: &str = "credentials.json";
+/// In-memory copy of the stored session, so the secret store isn't hit on every request
+static CACHED: Mutex<Option<AuthData>> = Mutex::new(None);
+
+/// Plain-text file used by earlier versions, migrated on startup
+const LEGACY_AUTH_FILE_NAME: &str = "credentials.json";
+
+/// Refresh the token once it has less than this much lifetime left
+const REFRESH_MARGIN_SECS: i64 = 24 * 60 * 60;
+
+/// Never refresh more often than this, or than half the lifetime of a short-lived token
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Longest sleep of the refresh loop; keeps it honest across system suspend
+const MAX_REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Wait after a refresh that failed for a transient reason (network, server down)
+const REFRESH_RETRY_DELAY: Duration = Duration::from_secs(60);
 
 /// Initialize the auth module with the app data directory.
 /// Must be called once at startup before any other auth function.
-pub fn init(app_data_dir: &PathBuf) {
-    let path = app_data_dir.join(AUTH_FILE_NAME);
-    AUTH_FILE_PATH.set(path).ok();
+pub fn init(app_data_dir: &Path) {
+    let backend = credentials::select_backend(app_data_dir);
+    log::info!("Storing credentials in {}", backend.name());
+
+    let stored = match backend.load() {
+        Ok(Some(secret)) => serde_json::from_str::<AuthData>(&secret)
+            .map_err(|e| log::error!("Failed to parse stored credentials: {}", e))
+            .ok(),
+        Ok(None) => None,
+        Err(e) => {
+            log::error!("{}", e);
+            None
+        }
+    };
+    *CACHED.lock().unwrap() = stored;
+    BACKEND.set(backend).ok();
+
+    migrate_legacy_file(&app_data_dir.join(LEGACY_AUTH_FILE_NAME));
 }
 
-fn auth_file() -> &'static PathBuf {
-    AUTH_FILE_PATH
+fn backend() -> &'static dyn CredentialBackend {
+    BACKEND
         .get()
         .expect("auth::init() must be called before using auth functions")
+        .as_ref()
+}
+
+/// Move credentials from the old plain `credentials.json` into the current backend
+fn migrate_legacy_file(path: &Path) {
+    if !path.exists() {
+        return;
+    }
+    let legacy = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str::<AuthData>(&content).ok());
+    if let Some(auth_data) = legacy {
+        if CACHED.lock().unwrap().is_none() {
+            if let Err(e) = store_credentials(&auth_data) {
+                // Keep the old file so the session isn't lost
+                log::error!("Failed to migrate credentials: {}", e);
+                return;
+            }
+            log::info!("Migrated credentials.json to {}", backend().name());
+        }
+    }
+    if let Err(e) = std::fs::remove_file(path) {
+        log::error!("Failed to remove legacy credentials file: {}", e);
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,6 +86,87 @@ pub struct AuthData {
     pub token: String,
     pub user_id: String,
     pub email: String,
+    /// Expiry taken from the token's `exp` claim
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl AuthData {
+    fn new(token: String, user_id: String, email: String) -> Self {
+        let expires_at = token_expiry(&token);
+        Self {
+            token,
+            user_id,
+            email,
+            expires_at,
+        }
+    }
+
+    /// Seconds until the token expires (negative once expired)
+    pub fn expires_in_secs(&self) -> Option<i64> {
+        self.expires_at.map(|at| (at - Utc::now()).num_seconds())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JwtClaims {
+    exp: i64,
+}
+
+/// Read the `exp` claim of a JWT. The signature is not verified — the server does that;
+/// this is only used to know when to refresh.
+fn token_expiry(token: &str) -> Option<DateTime<Utc>> {
+    let payload = token.split('.').nth(1)?;
+    let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    let claims: JwtClaims = serde_json::from_slice(&bytes).ok()?;
+    DateTime::from_timestamp(claims.exp, 0)
+}
+
+/// Where the stored session stands
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    /// No credentials stored
+    LoggedOut,
+    /// Token confirmed by the server
+    Valid,
+    /// Token not expired but not confirmed yet (server unreachable); refreshed when possible
+    Refreshable,
+    /// Token past its expiry; a new login is required
+    Expired,
+    /// Token rejected by the server before its expiry (password changed, user removed...)
+    Revoked,
+}
+
+impl SessionState {
+    /// Whether services can run with the stored token
+    pub fn is_usable(self) -> bool {
+        matches!(self, SessionState::Valid | SessionState::Refreshable)
+    }
+}
+
+/// Session state together with the stored credentials, if any
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub state: SessionState,
+    pub auth: Option<AuthData>,
+}
+
+/// Last known session state, shared with the tray and the upload worker
+static SESSION_STATE: Mutex<SessionState> = Mutex::new(SessionState::LoggedOut);
+
+pub fn session_state() -> SessionState {
+    *SESSION_STATE.lock().unwrap()
+}
+
+fn set_session_state(state: SessionState) {
+    let mut current = SESSION_STATE.lock().unwrap();
+    if *current != state {
+        log::info!("Session state: {:?} -> {:?}", *current, state);
+        *current = state;
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -41,7 +182,7 @@ struct PocketBaseUser {
 }
 
 /// Authenticate with PocketBase using email/password
-pub async fn login(server_url: &str, email: &str, password: &str) -> Result<AuthData, String> {
+pub async fn login(server_url: &str, email: &str, password: &str) -> Result<AuthData, AppError> {
     let client = Client::new();
     let url = format!(
         "{}/api/collections/users/auth-with-password",
@@ -56,32 +197,57 @@ pub async fn login(server_url: &str, email: &str, password: &str) -> Result<Auth
         }))
         .send()
         .await
-        .map_err(|e| format!("Connection error: {}", e))?;
+        .map_err(AppError::from_reqwest)?;
 
     if !response.status().is_success() {
-        let status = response.status();
-        let body = response.text().await.unwrap_or_default();
-        return Err(format!("Authentication failed ({}): {}", status, body));
+        // PocketBase answers 400 for a wrong identity/password pair
+        if response.status() == StatusCode::BAD_REQUEST {
+            return Err(AppError::InvalidCredentials);
+        }
+        return Err(AppError::from_response(response).await);
     }
 
     let auth_response: PocketBaseAuthResponse = response
         .json()
         .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
+        .map_err(|e| AppError::InvalidResponse(e.to_string()))?;
 
-    let auth_data = AuthData {
-        token: auth_response.token,
-        user_id: auth_response.record.id,
-        email: auth_response.record.email,
-    };
+    let auth_data = AuthData::new(
+        auth_response.token,
+        auth_response.record.id,
+        auth_response.record.email,
+    );
 
     store_credentials(&auth_data)?;
+    set_session_state(SessionState::Valid);
 
     Ok(auth_data)
 }
 
-/// Refresh the auth token
-pub async fn refresh_token(server_url: &str) -> Result<AuthData, String> {
+/// Refresh the auth token, updating the session state with the outcome
+pub async fn refresh_token(server_url: &str) -> Result<AuthData, AppError> {
+    let result = request_refresh(server_url).await;
+    match &result {
+        Ok(_) => set_session_state(SessionState::Valid),
+        Err(AppError::NotAuthenticated) => set_session_state(SessionState::LoggedOut),
+        Err(e) if e.kind() == ErrorKind::Auth || e.kind() == ErrorKind::Forbidden => {
+            let expired = get_stored_credentials()
+                .ok()
+                .and_then(|c| c.expires_in_secs())
+                .is_some_and(|secs| secs <= 0);
+            set_session_state(if expired {
+                SessionState::Expired
+            } else {
+                SessionState::Revoked
+            });
+        }
+        // Transient failures say nothing about the token itself
+        Err(_) => {}
+    }
+    result
+}
+
+async fn request_refresh(server_url: &str) -> Result<AuthData, AppError> {
     let current = get_stored_credentials()?;
     let client = Client::new();
     let url = format!(
@@ -94,69 +260,158 @@ pub async fn refresh_token(server_url: &str) -> Result<AuthData, String> {
         .header("Authorization", &current.token)
         .send()
         .await
-        .map_err(|e| format!("Connection error: {}", e))?;
+        .map_err(AppError::from_reqwest)?;
 
     if !response.status().is_success() {
-        return Err("Token refresh failed — please log in again".to_string());
+        return Err(AppError::from_response(response).await);
     }
 
     let auth_response: PocketBaseAuthResponse = response
         .json()
         .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
+        .map_err(|e| AppError::InvalidResponse(e.to_string()))?;
 
-    let auth_data = AuthData {
-        token: auth_response.token,
-        user_id: auth_response.record.id,
-        email: auth_response.record.email,
-    };
+    let auth_data = AuthData::new(
+        auth_response.token,
+        auth_response.record.id,
+        auth_response.record.email,
+    );
 
     store_credentials(&auth_data)?;
 
     Ok(auth_data)
 }
 
-/// Check if valid credentials are stored and token is still valid
-pub async fn check_auth(server_url: &str) -> Result<AuthData, String> {
-    let current = get_stored_credentials()?;
+/// Work out the session state from the stored credentials, verifying the token with the server
+pub async fn check_auth(server_url: &str) -> Session {
+    let current = match get_stored_credentials() {
+        Ok(current) => current,
+        Err(e) => {
+            if !matches!(e, AppError::NotAuthenticated) {
+                log::error!("Stored credentials unusable: {}", e);
+            }
+            set_session_state(SessionState::LoggedOut);
+            return Session {
+                state: SessionState::LoggedOut,
+                auth: None,
+            };
+        }
+    };
+
+    // PocketBase can't refresh a token past its expiry
+    if current.expires_in_secs().is_some_and(|secs| secs <= 0) {
+        set_session_state(SessionState::Expired);
+        return Session {
+            state: SessionState::Expired,
+            auth: Some(current),
+        };
+    }
+
     // Try to refresh to verify the token is still valid
     match refresh_token(server_url).await {
-        Ok(data) => Ok(data),
-        Err(_) => {
-            // Token might be expired but credentials exist
-            Ok(current)
+        Ok(data) => Session {
+            state: SessionState::Valid,
+            auth: Some(data),
+        },
+        Err(e) if e.kind() == ErrorKind::Auth || e.kind() == ErrorKind::Forbidden => Session {
+            state: session_state(),
+            auth: Some(current),
+        },
+        Err(e) => {
+            // Server unreachable: keep the token, it will be verified on the next refresh
+            log::warn!("Could not verify session: {}", e);
+            set_session_state(SessionState::Refreshable);
+            Session {
+                state: SessionState::Refreshable,
+                auth: Some(current),
+            }
         }
     }
 }
 
-/// Store credentials in a local JSON file
-fn store_credentials(auth_data: &AuthData) -> Result<(), String> {
-    let path = auth_file();
-    if let Some(parent) = path.parent() {
-        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+/// Keep the stored token fresh by refreshing it ahead of its expiry. Runs indefinitely;
+/// `server_url` is read on every refresh so configuration changes are picked up.
+pub async fn keep_session_fresh<F>(server_url: F)
+where
+    F: Fn() -> String,
+{
+    // When the token was last refreshed, and the shortest wait until the next refresh
+    let mut last_refresh: Option<(std::time::Instant, Duration)> = None;
+    // Token whose refresh was rejected; retrying it is pointless until the user logs in again
+    let mut rejected_token: Option<String> = None;
+
+    loop {
+        let wait = match get_stored_credentials() {
+            Ok(current) if rejected_token.as_deref() != Some(current.token.as_str()) => {
+                match current.expires_in_secs() {
+                    Some(remaining) => {
+                        let due_in = (remaining - REFRESH_MARGIN_SECS).max(0) as u64;
+                        let mut wait = Duration::from_secs(due_in);
+                        if let Some((last, interval)) = last_refresh {
+                            wait = wait.max(interval.saturating_sub(last.elapsed()));
+                        }
+                        wait
+                    }
+                    None => MAX_REFRESH_CHECK_INTERVAL,
+                }
+            }
+            _ => MAX_REFRESH_CHECK_INTERVAL,
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait.min(MAX_REFRESH_CHECK_INTERVAL)).await;
+            continue;
+        }
+
+        let token = get_token();
+        match refresh_token(&server_url()).await {
+            Ok(data) => {
+                let lifetime = data.expires_in_secs().unwrap_or_default();
+                log::info!("Auth token refreshed ahead of expiry (valid for {}s)", lifetime);
+                // A token that lives less than the interval is refreshed halfway through
+                let half_life = Duration::from_secs(lifetime.max(0) as u64 / 2);
+                let interval = MIN_REFRESH_INTERVAL.min(half_life);
+                last_refresh = Some((std::time::Instant::now(), interval));
+            }
+            Err(e) if e.kind() == ErrorKind::Auth || e.kind() == ErrorKind::Forbidden => {
+                log::warn!("Scheduled token refresh rejected: {}", e);
+                rejected_token = token;
+            }
+            Err(e) => {
+                log::warn!("Scheduled token refresh failed, will retry: {}", e);
+                tokio::time::sleep(REFRESH_RETRY_DELAY).await;
+            }
+        }
     }
-    let json = serde_json::to_string_pretty(auth_data).map_err(|e| e.to_string())?;
-    std::fs::write(path, json).map_err(|e| format!("Failed to store credentials: {}", e))?;
+}
+
+/// Store credentials in the credential backend
+fn store_credentials(auth_data: &AuthData) -> Result<(), AppError> {
+    let json = serde_json::to_string(auth_data).map_err(|e| AppError::Io(e.to_string()))?;
+    backend().store(&json)?;
+    *CACHED.lock().unwrap() = Some(auth_data.clone());
     Ok(())
 }
 
-/// Retrieve stored credentials from the local JSON file
-pub fn get_stored_credentials() -> Result<AuthData, String> {
-    let path = auth_file();
-    if !path.exists() {
-        return Err("No stored credentials found".to_string());
+/// Retrieve the stored credentials
+pub fn get_stored_credentials() -> Result<AuthData, AppError> {
+    let mut auth_data = CACHED
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or(AppError::NotAuthenticated)?;
+    // Credentials stored before expiry tracking existed
+    if auth_data.expires_at.is_none() {
+        auth_data.expires_at = token_expiry(&auth_data.token);
     }
-    let content =
-        std::fs::read_to_string(path).map_err(|e| format!("Failed to read credentials: {}", e))?;
-    serde_json::from_str(&content).map_err(|e| format!("Failed to parse credentials: {}", e))
+    Ok(auth_data)
 }
 
 /// Remove stored credentials (logout)
-pub fn logout() -> Result<(), String> {
-    let path = auth_file();
-    if path.exists() {
-        std::fs::remove_file(path).map_err(|e| format!("Failed to remove credentials: {}", e))?;
-    }
+pub fn logout() -> Result<(), AppError> {
+    backend().clear()?;
+    *CACHED.lock().unwrap() = None;
+    set_session_state(SessionState::LoggedOut);
     Ok(())
 }
 
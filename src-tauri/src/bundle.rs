@@ -0,0 +1,145 @@
+use crate::config::AppConfig;
+use crate::uploader::{RecentUpload, UploadStatus};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Marks a file as a settings bundle of this app
+const BUNDLE_FORMAT: &str = "inmobiliaria-inbox-bundle";
+
+/// Current version of the bundle layout
+const BUNDLE_VERSION: u32 = 1;
+
+/// Portable copy of the user's settings and upload history, used to move to a new computer.
+/// Never contains the session token.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SettingsBundle {
+    format: String,
+    version: u32,
+    pub exported_at: DateTime<Utc>,
+    /// Version of the app that wrote the bundle
+    #[serde(default)]
+    pub app_version: String,
+    /// The saved `config.json` fields, applied field by field on import
+    #[serde(default)]
+    pub config: Map<String, Value>,
+    #[serde(default)]
+    pub history: Vec<RecentUpload>,
+    /// Contents of the inbox's `.inboxignore`, if it had one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inbox_ignore: Option<String>,
+}
+
+impl SettingsBundle {
+    pub fn new(
+        config: &AppConfig,
+        history: Vec<RecentUpload>,
+        inbox_ignore: Option<String>,
+    ) -> Result<Self, String> {
+        let config = match serde_json::to_value(config).map_err(|e| e.to_string())? {
+            Value::Object(mut fields) => {
+                // The importing app writes its own schema version
+                fields.remove("version");
+                fields
+            }
+            _ => Map::new(),
+        };
+        Ok(Self {
+            format: BUNDLE_FORMAT.to_string(),
+            version: BUNDLE_VERSION,
+            exported_at: Utc::now(),
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            config,
+            // Pending entries refer to files that stay on the old computer
+            history: history
+                .into_iter()
+                .filter(|r| matches!(r.status, UploadStatus::Success | UploadStatus::Failed))
+                .collect(),
+            inbox_ignore,
+        })
+    }
+
+    /// Parse and check a bundle file
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let bundle: Self = serde_json::from_slice(bytes)
+            .map_err(|_| "El archivo no es un respaldo válido".to_string())?;
+        if bundle.format != BUNDLE_FORMAT {
+            return Err("El archivo no es un respaldo de Inmobiliaria Inbox".to_string());
+        }
+        if bundle.version > BUNDLE_VERSION {
+            return Err(
+                "El respaldo es de una versión más nueva de la app — actualizala primero"
+                    .to_string(),
+            );
+        }
+        Ok(bundle)
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, status: UploadStatus) -> RecentUpload {
+        RecentUpload {
+            name: name.to_string(),
+            status,
+            timestamp: "10:00:00".to_string(),
+            error: None,
+            error_kind: None,
+        }
+    }
+
+    #[test]
+    fn round_trip_keeps_only_finished_history() {
+        let history = vec![
+            entry("a.pdf", UploadStatus::Success),
+            entry("b.pdf", UploadStatus::Failed),
+            entry("c.pdf", UploadStatus::Pending),
+            entry("d.pdf", UploadStatus::Uploading),
+        ];
+        let bundle =
+            SettingsBundle::new(&AppConfig::default(), history, Some("*.bak\n".to_string()))
+                .unwrap();
+        let parsed = SettingsBundle::parse(bundle.to_json().unwrap().as_bytes()).unwrap();
+
+        let names: Vec<&str> = parsed.history.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["a.pdf", "b.pdf"]);
+        assert!(!parsed.config.contains_key("version"));
+        assert!(parsed.config.contains_key("inbox_path"));
+        assert_eq!(parsed.inbox_ignore.as_deref(), Some("*.bak\n"));
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(SettingsBundle::parse(b"not json").is_err());
+        assert!(SettingsBundle::parse(br#"{"server_url": "https://pb.example.com"}"#).is_err());
+        let other =
+            r#"{"format": "something-else", "version": 1, "exported_at": "2024-05-01T10:00:00Z"}"#;
+        assert!(SettingsBundle::parse(other.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn newer_bundle_is_rejected_and_older_fields_default() {
+        let newer = format!(
+            r#"{{"format": "{}", "version": {}, "exported_at": "2024-05-01T10:00:00Z"}}"#,
+            BUNDLE_FORMAT,
+            BUNDLE_VERSION + 1
+        );
+        let err = SettingsBundle::parse(newer.as_bytes()).unwrap_err();
+        assert!(err.contains("versión más nueva"), "{}", err);
+
+        // Written before `app_version`, `history` and `.inboxignore` were added
+        let minimal = format!(
+            r#"{{"format": "{}", "version": 1, "exported_at": "2024-05-01T10:00:00Z"}}"#,
+            BUNDLE_FORMAT
+        );
+        let bundle = SettingsBundle::parse(minimal.as_bytes()).unwrap();
+        assert!(bundle.config.is_empty() && bundle.history.is_empty());
+        assert_eq!(bundle.inbox_ignore, None);
+    }
+}
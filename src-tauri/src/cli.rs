@@ -0,0 +1,207 @@
+use serde_json::{Map, Value};
+
+/// A config field that can be set for a single run from the command line or the environment
+struct Override {
+    flag: &'static str,
+    env: &'static str,
+    field: &'static str,
+    numeric: bool,
+}
+
+const OVERRIDES: &[Override] = &[
+    Override {
+        flag: "--server",
+        env: "INMOBILIARIA_SERVER_URL",
+        field: "server_url",
+        numeric: false,
+    },
+    Override {
+        flag: "--web",
+        env: "INMOBILIARIA_WEB_URL",
+        field: "web_url",
+        numeric: false,
+    },
+    Override {
+        flag: "--inbox",
+        env: "INMOBILIARIA_INBOX",
+        field: "inbox_path",
+        numeric: false,
+    },
+    Override {
+        flag: "--max-concurrent",
+        env: "INMOBILIARIA_MAX_CONCURRENT",
+        field: "max_concurrent_uploads",
+        numeric: true,
+    },
+];
+
+/// How the app was launched
+#[derive(Debug, Default)]
+pub struct LaunchOptions {
+    /// Started by the OS at login (`--autostarted`, passed by the autostart plugin):
+    /// stay in the tray and only speak up if the user has to log in
+    pub autostarted: bool,
+    /// Config values that take precedence over `config.json` for this run only
+    pub overrides: Map<String, Value>,
+}
+
+impl LaunchOptions {
+    /// Read the process arguments and the environment. Command-line flags win over variables.
+    pub fn from_env() -> Self {
+        Self::parse(std::env::args().skip(1), |name| std::env::var(name).ok())
+    }
+
+    fn parse(args: impl IntoIterator<Item = String>, env: impl Fn(&str) -> Option<String>) -> Self {
+        let mut options = Self::default();
+        for o in OVERRIDES {
+            if let Some(raw) = env(o.env).filter(|v| !v.trim().is_empty()) {
+                options.set(o, &raw, o.env);
+            }
+        }
+
+        let mut args = args.into_iter().peekable();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--autostarted" => {
+                    options.autostarted = true;
+                    continue;
+                }
+                "--help" | "-h" => {
+                    print_usage();
+                    std::process::exit(0);
+                }
+                _ => {}
+            }
+
+            // Both `--flag value` and `--flag=value`
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let Some(o) = OVERRIDES.iter().find(|o| o.flag == flag) else {
+                log::warn!("Ignoring unknown argument '{}'", arg);
+                continue;
+            };
+            // A flag isn't a value: `--inbox --autostarted` is missing the folder, and
+            // `--autostarted` still applies
+            let value = inline.or_else(|| args.next_if(|next| !next.starts_with("--")));
+            match value.filter(|raw| !raw.starts_with("--")) {
+                Some(raw) => options.set(o, &raw, o.flag),
+                None => log::warn!("Missing value for {}", o.flag),
+            }
+        }
+        options
+    }
+
+    fn set(&mut self, o: &Override, raw: &str, source: &str) {
+        let raw = raw.trim();
+        let value = if o.numeric {
+            match raw.parse::<u64>() {
+                Ok(n) => Value::from(n),
+                Err(_) => {
+                    log::warn!("Ignoring {}: '{}' is not a number", source, raw);
+                    return;
+                }
+            }
+        } else {
+            Value::from(raw)
+        };
+        log::info!("{} overrides {} for this run", source, o.field);
+        self.overrides.insert(o.field.to_string(), value);
+    }
+}
+
+fn print_usage() {
+    attach_console();
+    println!("Uso: inmobiliaria-tray-app [opciones]\n");
+    println!("  --autostarted            Iniciar en segundo plano, sin abrir ventanas");
+    for o in OVERRIDES {
+        let usage = format!("{} <valor>", o.flag);
+        println!("  {:<24} Valor de {} (también {})", usage, o.field, o.env);
+    }
+    println!("  -h, --help               Mostrar esta ayuda");
+}
+
+/// Release builds on Windows are GUI apps without a console, so `println!` goes nowhere.
+/// Borrow the console of the shell that started us, if there is one.
+#[cfg(windows)]
+fn attach_console() {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+    }
+    const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+
+    // SAFETY: no pointers are passed; failing just leaves stdout unattached
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(not(windows))]
+fn attach_console() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str], env: &[(&str, &str)]) -> LaunchOptions {
+        let env: Vec<(String, String)> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        LaunchOptions::parse(args.iter().map(|a| a.to_string()), |name| {
+            env.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone())
+        })
+    }
+
+    #[test]
+    fn flag_with_equals() {
+        let options = parse(&["--server=https://pb.example.com"], &[]);
+        assert_eq!(options.overrides["server_url"], "https://pb.example.com");
+    }
+
+    #[test]
+    fn flag_with_separate_value() {
+        let options = parse(&["--autostarted", "--max-concurrent", "3"], &[]);
+        assert!(options.autostarted);
+        assert_eq!(options.overrides["max_concurrent_uploads"], 3);
+    }
+
+    #[test]
+    fn flag_without_value_is_ignored() {
+        let options = parse(&["--inbox"], &[]);
+        assert!(options.overrides.is_empty());
+    }
+
+    #[test]
+    fn flag_is_not_taken_as_a_value() {
+        let options = parse(&["--inbox", "--autostarted"], &[]);
+        assert!(options.autostarted);
+        assert!(options.overrides.is_empty());
+
+        let options = parse(&["--server=--web", "--web", "https://example.com"], &[]);
+        assert!(!options.overrides.contains_key("server_url"));
+        assert_eq!(options.overrides["web_url"], "https://example.com");
+    }
+
+    #[test]
+    fn non_numeric_value_is_ignored() {
+        let options = parse(
+            &["--max-concurrent", "many"],
+            &[("INMOBILIARIA_MAX_CONCURRENT", "lots")],
+        );
+        assert!(!options.overrides.contains_key("max_concurrent_uploads"));
+    }
+
+    #[test]
+    fn flag_wins_over_environment() {
+        let env = [
+            ("INMOBILIARIA_INBOX", "/from/env"),
+            ("INMOBILIARIA_WEB_URL", "https://env.example.com"),
+        ];
+        let options = parse(&["--inbox", "/from/flag"], &env);
+        assert_eq!(options.overrides["inbox_path"], "/from/flag");
+        assert_eq!(options.overrides["web_url"], "https://env.example.com");
+    }
+}
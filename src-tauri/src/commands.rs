@@ -1,9 +1,15 @@
-use crate::auth::{self, AuthData};
-use crate::config::{AppConfig, ConfigManager};
-use crate::uploader::{RecentUpload, UploadManager};
+use crate::auth::{self, AuthData, SessionState};
+use crate::bundle::SettingsBundle;
+use crate::config::{self, AppConfig, ConfigManager, LockedField};
+use crate::error::{CommandError, FieldError};
+use crate::services::{ReconcileReport, ServiceSupervisor, TaskHealth};
+use crate::storage;
+use crate::uploader::{RecentUpload, UploadManager, UploadProgress};
+use crate::validation;
+use crate::watcher::{self, IgnoreRules};
 use serde::Serialize;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use tauri::State;
 use tauri_plugin_dialog::DialogExt;
 
@@ -11,18 +17,39 @@ use tauri_plugin_dialog::DialogExt;
 pub struct AppState {
     pub config_manager: ConfigManager,
     pub upload_manager: Arc<UploadManager>,
-    pub services_running: AtomicBool,
+    pub services: ServiceSupervisor,
+    /// Problems found in the saved config, cleared once a valid config is saved
+    pub config_errors: Mutex<Vec<FieldError>>,
+}
+
+/// Session state plus the stored credentials and how long they remain valid
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub state: SessionState,
+    #[serde(flatten)]
+    pub auth: Option<AuthData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in_secs: Option<i64>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct StatusInfo {
     pub authenticated: bool,
+    pub session: SessionState,
     pub email: Option<String>,
     pub online: bool,
+    pub services_running: bool,
+    /// Health of the watcher and upload worker
+    pub tasks: Vec<TaskHealth>,
+    /// Files the periodic inbox scan found after the watcher missed them
+    pub reconcile: ReconcileReport,
     pub uploading: bool,
+    pub active_uploads: usize,
     pub queue_size: usize,
+    pub progress: Vec<UploadProgress>,
     pub recent: Vec<RecentUpload>,
     pub config: AppConfig,
+    pub config_errors: Vec<FieldError>,
 }
 
 #[tauri::command]
@@ -32,42 +59,75 @@ pub async fn login(
     server_url: Option<String>,
     web_url: Option<String>,
     state: State<'_, AppState>,
-) -> Result<AuthData, String> {
-    use crate::config::{DEFAULT_SERVER_URL, DEFAULT_WEB_URL};
+) -> Result<AuthData, CommandError> {
+    // Empty fields fall back to the defaults; fields locked by the admin policy can't be changed
+    let config = state.config_manager.get();
+    let defaults = state.config_manager.defaults();
+    let server = if state.config_manager.is_locked("server_url") {
+        config.server_url
+    } else {
+        server_url
+            .filter(|s| !s.is_empty())
+            .unwrap_or(defaults.server_url)
+    };
+    let web = if state.config_manager.is_locked("web_url") {
+        config.web_url
+    } else {
+        web_url.filter(|s| !s.is_empty()).unwrap_or(defaults.web_url)
+    };
 
-    let server = server_url
-        .filter(|s| !s.is_empty())
-        .unwrap_or_else(|| DEFAULT_SERVER_URL.to_string());
-    let web = web_url
-        .filter(|s| !s.is_empty())
-        .unwrap_or_else(|| DEFAULT_WEB_URL.to_string());
+    // Check the URLs before saving them; the other fields can't be edited from the login form
+    {
+        let mut candidate = state.config_manager.get();
+        candidate.server_url = server.clone();
+        candidate.web_url = web.clone();
+        let errors: Vec<FieldError> = validation::validate(&candidate)
+            .into_iter()
+            .filter(|e| e.field == "server_url" || e.field == "web_url")
+            .collect();
+        if !errors.is_empty() {
+            return Err(CommandError::invalid(errors));
+        }
+        validation::check_server_health(&server)
+            .await
+            .map_err(|e| CommandError::invalid(vec![e]))?;
+    }
 
     // Save URLs to config
     {
-        let mut config = state.config_manager.get();
+        let old = state.config_manager.get();
+        let mut config = old.clone();
         config.server_url = server.clone();
         config.web_url = web;
         state.config_manager.save(config)?;
+        state.services.apply_config(&old, &state.config_manager);
     }
 
     // Authenticate
     let auth_data = auth::login(&server, &email, &password).await?;
 
-    // Ensure inbox folder exists
-    state.config_manager.ensure_inbox_folder()?;
+    // Uploads paused by an expired session can continue with the new token
+    state.upload_manager.resume_after_login();
 
     Ok(auth_data)
 }
 
 #[tauri::command]
-pub async fn logout() -> Result<(), String> {
-    auth::logout()
+pub async fn logout(state: State<'_, AppState>) -> Result<(), String> {
+    // Nothing should keep watching or uploading for a user that is gone
+    state.services.stop();
+    Ok(auth::logout()?)
 }
 
 #[tauri::command]
-pub async fn check_auth(state: State<'_, AppState>) -> Result<AuthData, String> {
+pub async fn check_auth(state: State<'_, AppState>) -> Result<SessionInfo, String> {
     let config = state.config_manager.get();
-    auth::check_auth(&config.server_url).await
+    let session = auth::check_auth(&config.server_url).await;
+    Ok(SessionInfo {
+        state: session.state,
+        expires_in_secs: session.auth.as_ref().and_then(|a| a.expires_in_secs()),
+        auth: session.auth,
+    })
 }
 
 #[tauri::command]
@@ -75,9 +135,37 @@ pub async fn get_config(state: State<'_, AppState>) -> Result<AppConfig, String>
     Ok(state.config_manager.get())
 }
 
+/// Config fields set by the admin policy or for this run, shown read-only in the settings window
+#[tauri::command]
+pub async fn get_locked_fields(state: State<'_, AppState>) -> Result<Vec<LockedField>, String> {
+    Ok(state.config_manager.locked_fields())
+}
+
 #[tauri::command]
-pub async fn save_config(config: AppConfig, state: State<'_, AppState>) -> Result<(), String> {
+pub async fn save_config(
+    config: AppConfig,
+    state: State<'_, AppState>,
+) -> Result<(), CommandError> {
+    let old = state.config_manager.get();
+    // Locked fields can't be edited here and keep their value when saved
+    let mut errors: Vec<FieldError> = validation::validate(&config)
+        .into_iter()
+        .filter(|e| !state.config_manager.is_locked(&e.field))
+        .collect();
+    // Only a new server has to be reachable; saving other settings works offline
+    if config.server_url != old.server_url && !errors.iter().any(|e| e.field == "server_url") {
+        if let Err(e) = validation::check_server_health(&config.server_url).await {
+            errors.push(e);
+        }
+    }
+    if !errors.is_empty() {
+        return Err(CommandError::invalid(errors));
+    }
+
     state.config_manager.save(config)?;
+    state.config_errors.lock().unwrap().clear();
+    // Running services pick up the new folder and upload settings right away
+    state.services.apply_config(&old, &state.config_manager);
     Ok(())
 }
 
@@ -85,15 +173,23 @@ pub async fn save_config(config: AppConfig, state: State<'_, AppState>) -> Resul
 pub async fn get_status(state: State<'_, AppState>) -> Result<StatusInfo, String> {
     let config = state.config_manager.get();
     let credentials = auth::get_stored_credentials();
+    let session = auth::session_state();
 
     Ok(StatusInfo {
-        authenticated: credentials.is_ok(),
+        authenticated: credentials.is_ok() && session.is_usable(),
+        session,
         email: credentials.ok().map(|c| c.email),
         online: state.upload_manager.is_online(),
+        services_running: state.services.is_running(),
+        tasks: state.services.health(),
+        reconcile: state.services.reconcile_report(),
         uploading: state.upload_manager.is_uploading(),
+        active_uploads: state.upload_manager.active_count(),
         queue_size: state.upload_manager.queue_size(),
+        progress: state.upload_manager.get_progress(),
         recent: state.upload_manager.get_recent(),
         config,
+        config_errors: state.config_errors.lock().unwrap().clone(),
     })
 }
 
@@ -117,6 +213,230 @@ pub async fn select_folder(app: tauri::AppHandle) -> Result<Option<String>, Stri
         .map_err(|e| format!("Dialog error: {}", e))
 }
 
+/// Most files listed by the ignore rules preview
+const MAX_PREVIEW_FILES: usize = 200;
+
+/// A file in the inbox and whether the ignore rules skip it
+#[derive(Debug, Serialize)]
+pub struct IgnorePreviewEntry {
+    pub name: String,
+    pub ignored: bool,
+    /// The pattern that decided, `!pattern` for files brought back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+}
+
+/// Show which files now in the inbox would be ignored with `patterns`, before saving them.
+/// The `.inboxignore` file in the folder is applied as well.
+#[tauri::command]
+pub async fn preview_ignore_rules(
+    patterns: Vec<String>,
+    state: State<'_, AppState>,
+) -> Result<Vec<IgnorePreviewEntry>, CommandError> {
+    validation::check_ignore_patterns(&patterns).map_err(|e| CommandError::invalid(vec![e]))?;
+
+    let config = state.config_manager.get();
+    let inbox_path = PathBuf::from(&config.inbox_path);
+    let rules = IgnoreRules::load(&inbox_path, &patterns, config.watch_subfolders);
+    let mut files = watcher::list_files(&inbox_path, config.watch_subfolders);
+    files.sort();
+    Ok(files
+        .iter()
+        .take(MAX_PREVIEW_FILES)
+        .map(|path| IgnorePreviewEntry {
+            // Files in subfolders are shown with their folder, e.g. `Facturas/enero.pdf`
+            name: watcher::inbox_relative(&inbox_path, path),
+            ignored: rules.is_ignored(path),
+            rule: rules.matching_rule(path),
+        })
+        .collect())
+}
+
+/// What an import changed, for the settings window
+#[derive(Debug, Serialize)]
+pub struct ImportSummary {
+    /// Config fields taken from the bundle
+    pub applied: Vec<String>,
+    /// Config fields left as they were, with the reason
+    pub skipped: Vec<FieldError>,
+    /// Upload history entries added
+    pub history: usize,
+    /// Whether the bundle's `.inboxignore` was written to the inbox folder
+    pub inbox_ignore: bool,
+    /// Whether it replaced different rules, which were kept in `.inboxignore.bak`
+    pub inbox_ignore_backup: bool,
+}
+
+/// Save the config, upload history and `.inboxignore` to a file chosen by the user.
+/// The token is not included.
+#[tauri::command]
+pub async fn export_settings(
+    app: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Option<String>, String> {
+    let inbox_path = PathBuf::from(state.config_manager.get().inbox_path);
+    let inbox_ignore = std::fs::read_to_string(inbox_path.join(watcher::IGNORE_FILE_NAME)).ok();
+    let bundle = SettingsBundle::new(
+        &state.config_manager.user_config(),
+        state.upload_manager.get_recent(),
+        inbox_ignore,
+    )?;
+    let json = bundle.to_json()?;
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    app.dialog()
+        .file()
+        .set_title("Exportar configuración")
+        .set_file_name(format!(
+            "inmobiliaria-inbox-{}.json",
+            chrono::Local::now().format("%Y-%m-%d")
+        ))
+        .add_filter("JSON", &["json"])
+        .save_file(move |file| {
+            let _ = tx.send(file.and_then(|f| f.into_path().ok()));
+        });
+    let Some(path) = rx.recv().map_err(|e| format!("Dialog error: {}", e))? else {
+        return Ok(None);
+    };
+
+    // A plain write: a `.bak` next to a file the user picked would only be clutter
+    std::fs::write(&path, json.as_bytes())
+        .map_err(|e| format!("No se pudo guardar el respaldo: {}", e))?;
+    log::info!("Exported settings to {:?}", path);
+    Ok(Some(path.to_string_lossy().to_string()))
+}
+
+/// Load a bundle written by `export_settings`. Fields that are invalid on this computer
+/// or locked by the admin policy keep their current value.
+#[tauri::command]
+pub async fn import_settings(
+    app: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Option<ImportSummary>, CommandError> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    app.dialog()
+        .file()
+        .set_title("Importar configuración")
+        .add_filter("JSON", &["json"])
+        .pick_file(move |file| {
+            let _ = tx.send(file.and_then(|f| f.into_path().ok()));
+        });
+    let Some(path) = rx.recv().map_err(|e| format!("Dialog error: {}", e))? else {
+        return Ok(None);
+    };
+
+    let bytes = std::fs::read(&path).map_err(|e| format!("No se pudo leer el archivo: {}", e))?;
+    let bundle = SettingsBundle::parse(&bytes)?;
+
+    let old = state.config_manager.get();
+    let current = state.config_manager.user_config();
+    let (mut candidate, rejected) = config::merge_fields(&current, bundle.config.clone());
+    let mut skipped: Vec<FieldError> = rejected
+        .into_iter()
+        .map(|field| FieldError {
+            field,
+            code: Some("invalid_value".to_string()),
+            message: "Valor inválido en el respaldo".to_string(),
+        })
+        .collect();
+
+    // Paths and URLs from the old computer may not work here; keep the current value for those
+    let invalid: Vec<FieldError> = validation::validate(&candidate)
+        .into_iter()
+        .filter(|e| bundle.config.contains_key(&e.field))
+        .collect();
+    candidate = config::restore_fields(&candidate, &current, |key| {
+        invalid.iter().any(|e| e.field == key)
+    });
+    skipped.extend(invalid);
+
+    // Fields this version doesn't know about were dropped by `merge_fields`
+    let known = match serde_json::to_value(&current) {
+        Ok(serde_json::Value::Object(fields)) => fields,
+        _ => serde_json::Map::new(),
+    };
+    let mut applied = Vec::new();
+    for field in bundle.config.keys() {
+        if field == "version" || !known.contains_key(field) {
+            continue;
+        }
+        if skipped.iter().any(|e| &e.field == field) {
+            continue;
+        }
+        if let Some(source) = state.config_manager.lock_source(field) {
+            skipped.push(FieldError {
+                field: field.clone(),
+                code: Some("locked".to_string()),
+                message: source.describe().to_string(),
+            });
+        } else {
+            applied.push(field.clone());
+        }
+    }
+
+    state.config_manager.save(candidate)?;
+    state.config_errors.lock().unwrap().clear();
+
+    // Written before the watcher restarts so it starts with these rules
+    let mut inbox_ignore = false;
+    let mut inbox_ignore_backup = false;
+    if let Some(contents) = &bundle.inbox_ignore {
+        let inbox_path = PathBuf::from(state.config_manager.get().inbox_path);
+        match restore_inbox_ignore(&inbox_path, contents) {
+            Ok(backup) => {
+                inbox_ignore = true;
+                inbox_ignore_backup = backup;
+            }
+            Err(e) => log::warn!("{}", e),
+        }
+    }
+    state.services.apply_config(&old, &state.config_manager);
+    let history = state.upload_manager.import_recent(bundle.history);
+
+    log::info!(
+        "Imported settings from {:?}: {} field(s), {} skipped, {} history entries",
+        path,
+        applied.len(),
+        skipped.len(),
+        history
+    );
+    Ok(Some(ImportSummary {
+        applied,
+        skipped,
+        history,
+        inbox_ignore,
+        inbox_ignore_backup,
+    }))
+}
+
+/// Write the `.inboxignore` from a bundle into the inbox folder. Rules already there are kept
+/// in `.inboxignore.bak`, a hidden file that is never uploaded. Returns whether that happened.
+fn restore_inbox_ignore(inbox_path: &Path, contents: &str) -> Result<bool, String> {
+    let file = inbox_path.join(watcher::IGNORE_FILE_NAME);
+    let current = std::fs::read_to_string(&file).ok();
+    if current.as_deref() == Some(contents) {
+        return Ok(false);
+    }
+    // Writing would create a missing folder, which is the watcher's call
+    if !inbox_path.is_dir() {
+        return Err(format!(
+            "Inbox folder {:?} is not available, {} not restored",
+            inbox_path,
+            watcher::IGNORE_FILE_NAME
+        ));
+    }
+    storage::write_atomic(&file, contents.as_bytes())
+        .map_err(|e| format!("Could not write {:?} from the bundle: {}", file, e))?;
+    if current.is_some() {
+        log::warn!(
+            "Replaced {:?}, the previous rules are in {:?}",
+            file,
+            storage::backup_path(&file)
+        );
+    }
+    Ok(current.is_some())
+}
+
 #[tauri::command]
 pub async fn set_autostart(app: tauri::AppHandle, enabled: bool) -> Result<(), String> {
     use tauri_plugin_autostart::ManagerExt;
@@ -130,11 +450,49 @@ pub async fn set_autostart(app: tauri::AppHandle, enabled: bool) -> Result<(), S
 
 #[tauri::command]
 pub async fn start_services_cmd(app: tauri::AppHandle, state: State<'_, AppState>) -> Result<(), String> {
-    if state.services_running.swap(true, Ordering::SeqCst) {
-        // Already running
-        return Ok(());
-    }
     let upload_manager = state.upload_manager.clone();
     crate::start_services(&app, upload_manager).await;
     Ok(())
 }
+
+#[tauri::command]
+pub async fn stop_services(state: State<'_, AppState>) -> Result<(), String> {
+    state.services.stop();
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn restart_services(app: tauri::AppHandle, state: State<'_, AppState>) -> Result<(), String> {
+    state.services.stop();
+    let upload_manager = state.upload_manager.clone();
+    crate::start_services(&app, upload_manager).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restored_inbox_ignore_keeps_the_rules_it_replaces() {
+        let inbox =
+            std::env::temp_dir().join(format!("inbox-commands-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&inbox).unwrap();
+        let file = inbox.join(watcher::IGNORE_FILE_NAME);
+
+        assert_eq!(restore_inbox_ignore(&inbox, "*.tmp\n"), Ok(false));
+        std::fs::write(&file, "borradores/\n").unwrap();
+        assert_eq!(restore_inbox_ignore(&inbox, "*.tmp\n"), Ok(true));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "*.tmp\n");
+        assert_eq!(
+            std::fs::read_to_string(storage::backup_path(&file)).unwrap(),
+            "borradores/\n"
+        );
+        // The same rules again change nothing
+        assert_eq!(restore_inbox_ignore(&inbox, "*.tmp\n"), Ok(false));
+
+        std::fs::remove_dir_all(&inbox).unwrap();
+        assert!(restore_inbox_ignore(&inbox, "*.tmp\n").is_err());
+        assert!(!inbox.exists());
+    }
+}
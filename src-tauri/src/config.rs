@@ -1,33 +1,94 @@
+use crate::policy::Policy;
+use crate::storage;
+use crate::validation;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::Duration;
 
 const DEFAULT_INBOX_FOLDER_NAME: &str = "Inmobiliaria Inbox";
 pub const DEFAULT_SERVER_URL: &str = "https://pocketbase.af.irrequieto.cloud";
 pub const DEFAULT_WEB_URL: &str = "https://arielfernandez.uy";
+pub const DEFAULT_MAX_CONCURRENT_UPLOADS: usize = 2;
+pub const DEFAULT_FILE_SETTLE_SECS: u64 = 2;
+pub const DEFAULT_POLL_INTERVAL_SECS: u64 = 5;
 
+/// Current version of the `config.json` schema
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Steps that upgrade a config file, `MIGRATIONS[n]` goes from version `n` to `n + 1`
+const MIGRATIONS: &[fn(&mut Map<String, Value>)] = &[migrate_v0_to_v1, migrate_v1_to_v2];
+
+/// How the inbox folder is watched for new files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchMode {
+    /// OS events, or polling if the folder is on a network share
+    #[default]
+    Auto,
+    /// OS events only
+    Native,
+    /// Scan the folder every `poll_interval_secs`
+    Poll,
+}
+
+/// Missing fields take their value from `AppConfig::default()`
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct AppConfig {
+    /// Schema version, see `CONFIG_VERSION`
+    pub version: u32,
     pub server_url: String,
     pub web_url: String,
     pub inbox_path: String,
     pub delete_after_upload: bool,
     pub auto_start: bool,
+    /// Number of files uploaded in parallel
+    pub max_concurrent_uploads: usize,
+    /// Seconds a new file must stay unchanged before it's uploaded
+    pub file_settle_secs: u64,
+    pub watch_mode: WatchMode,
+    /// Seconds between scans when the folder is polled
+    pub poll_interval_secs: u64,
+    /// Extra `.gitignore`-style patterns for files that shouldn't be uploaded
+    pub ignore_patterns: Vec<String>,
+    /// Upload files in subfolders of the inbox too, tagged with their folder
+    pub watch_subfolders: bool,
 }
 
 impl Default for AppConfig {
     fn default() -> Self {
         let default_path = dirs_default_inbox();
         Self {
+            version: CONFIG_VERSION,
             server_url: DEFAULT_SERVER_URL.to_string(),
             web_url: DEFAULT_WEB_URL.to_string(),
             inbox_path: default_path,
             delete_after_upload: true,
             auto_start: true,
+            max_concurrent_uploads: DEFAULT_MAX_CONCURRENT_UPLOADS,
+            file_settle_secs: DEFAULT_FILE_SETTLE_SECS,
+            watch_mode: WatchMode::Auto,
+            poll_interval_secs: DEFAULT_POLL_INTERVAL_SECS,
+            ignore_patterns: Vec::new(),
+            watch_subfolders: true,
         }
     }
 }
 
+impl AppConfig {
+    /// How long a new file must stay unchanged before it's uploaded
+    pub fn file_settle(&self) -> Duration {
+        Duration::from_secs(self.file_settle_secs)
+    }
+
+    /// Time between scans when the inbox folder is polled
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+}
+
 fn dirs_default_inbox() -> String {
     let home = dirs::document_dir()
         .or_else(dirs::home_dir)
@@ -37,57 +98,254 @@ fn dirs_default_inbox() -> String {
         .to_string()
 }
 
-/// Persistent configuration manager using a JSON file in the app data directory
+/// Why a config field can't be edited in the settings window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockSource {
+    /// Locked by the admin policy file
+    Policy,
+    /// Set from the command line or the environment for this run
+    Override,
+}
+
+impl LockSource {
+    /// Explanation shown next to the field
+    pub fn describe(self) -> &'static str {
+        match self {
+            LockSource::Policy => "Definido por el administrador",
+            LockSource::Override => "Fijado al iniciar (línea de comandos o variable de entorno)",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LockedField {
+    pub field: String,
+    pub source: LockSource,
+}
+
+/// Persistent configuration manager using a JSON file in the app data directory.
+/// The effective config layers, from lowest to highest precedence: defaults, policy defaults,
+/// what the user saved, command-line/environment overrides and locked policy values.
 pub struct ConfigManager {
+    /// Effective config with overrides and locked policy values applied
     config: Mutex<AppConfig>,
+    /// What the user saved, as written to `config.json`
+    user_config: Mutex<AppConfig>,
     config_path: PathBuf,
+    policy: Policy,
+    /// Values from the command line or environment, valid for this run only
+    overrides: Map<String, Value>,
 }
 
 impl ConfigManager {
-    pub fn new(app_data_dir: PathBuf) -> Self {
+    pub fn new(app_data_dir: PathBuf, policy: Policy, overrides: Map<String, Value>) -> Self {
         let config_path = app_data_dir.join("config.json");
-        let config = if config_path.exists() {
-            match std::fs::read_to_string(&config_path) {
-                Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
-                Err(_) => AppConfig::default(),
-            }
+        let defaults = policy.defaults();
+        let (config, repaired) = if config_path.exists() {
+            load_config(&config_path, &defaults)
         } else {
-            AppConfig::default()
+            (defaults, false)
         };
 
-        Self {
-            config: Mutex::new(config),
+        // Drop invalid overrides so they're neither applied nor re-checked on every save
+        let mut overrides = overrides;
+        validation::drop_invalid_values(&mut overrides, "override");
+
+        let manager = Self {
+            config: Mutex::new(effective_config(&config, &overrides, &policy)),
+            user_config: Mutex::new(config.clone()),
             config_path,
+            policy,
+            overrides,
+        };
+        // Write back migrated or repaired configs so they're only fixed up once
+        if repaired {
+            if let Err(e) = manager.save(config) {
+                log::error!("Failed to save upgraded config: {}", e);
+            }
         }
+        manager
     }
 
     pub fn get(&self) -> AppConfig {
         self.config.lock().unwrap().clone()
     }
 
-    pub fn save(&self, new_config: AppConfig) -> Result<(), String> {
-        // Ensure parent directory exists
-        if let Some(parent) = self.config_path.parent() {
-            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    /// The config as the user saved it, without overrides or locked policy values
+    pub fn user_config(&self) -> AppConfig {
+        self.user_config.lock().unwrap().clone()
+    }
+
+    /// Defaults for fields the user leaves empty, including the policy defaults
+    pub fn defaults(&self) -> AppConfig {
+        self.policy.defaults()
+    }
+
+    /// Whether a field is fixed by the admin policy or an override for this run
+    pub fn is_locked(&self, field: &str) -> bool {
+        self.lock_source(field).is_some()
+    }
+
+    /// What fixes a field's value. The policy is applied last, so it wins over an override.
+    pub fn lock_source(&self, field: &str) -> Option<LockSource> {
+        if self.policy.is_locked(field) {
+            Some(LockSource::Policy)
+        } else if self.overrides.contains_key(field) {
+            Some(LockSource::Override)
+        } else {
+            None
         }
-        let json = serde_json::to_string_pretty(&new_config).map_err(|e| e.to_string())?;
-        std::fs::write(&self.config_path, json).map_err(|e| e.to_string())?;
-        *self.config.lock().unwrap() = new_config;
+    }
+
+    pub fn locked_fields(&self) -> Vec<LockedField> {
+        let mut fields = self.policy.locked_fields();
+        fields.extend(self.overrides.keys().cloned());
+        fields.sort();
+        fields.dedup();
+        fields
+            .into_iter()
+            .filter_map(|field| {
+                let source = self.lock_source(&field)?;
+                Some(LockedField { field, source })
+            })
+            .collect()
+    }
+
+    pub fn save(&self, new_config: AppConfig) -> Result<(), String> {
+        // Held until the end, so overlapping saves (e.g. settings and an import) take turns
+        let mut saved = self.user_config.lock().unwrap();
+        // Locked fields can't be changed; keep the user's own value in the file
+        let mut user_config = restore_fields(&new_config, &saved, |key| self.is_locked(key));
+        user_config.version = CONFIG_VERSION;
+        let json = serde_json::to_string_pretty(&user_config).map_err(|e| e.to_string())?;
+        storage::write_atomic(&self.config_path, json.as_bytes())
+            .map_err(|e| format!("Failed to write config: {}", e))?;
+        let effective = effective_config(&user_config, &self.overrides, &self.policy);
+        *self.config.lock().unwrap() = effective;
+        *saved = user_config;
         Ok(())
     }
+}
+
+/// Read `config.json`, upgrading old versions and keeping every field that is still valid.
+/// Anything that had to be dropped, or a damaged file replaced by its `.bak`, is preserved in
+/// a backup next to the file.
+/// Returns whether the result differs from the file and should be written back.
+fn load_config(path: &Path, defaults: &AppConfig) -> (AppConfig, bool) {
+    let parsed = storage::load_with_backup(path, |bytes| {
+        serde_json::from_slice::<Map<String, Value>>(bytes)
+    });
+    let Some((mut fields, recovered)) = parsed else {
+        log::error!("Config file is unreadable, using defaults");
+        backup_config(path);
+        return (defaults.clone(), true);
+    };
+
+    // Files written before versioning have no `version` field
+    let version = fields
+        .get("version")
+        .and_then(Value::as_u64)
+        .map_or(0, |v| v as u32);
+    if version > CONFIG_VERSION {
+        log::warn!(
+            "Config file is version {}, newer than this app ({}); reading it as is",
+            version,
+            CONFIG_VERSION
+        );
+    }
+    for (from, migrate) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        log::info!("Migrating config from version {} to {}", from, from + 1);
+        migrate(&mut fields);
+    }
+
+    let (config, rejected) = merge_fields(defaults, fields);
+    if !rejected.is_empty() {
+        log::warn!("Ignored invalid config values: {}", rejected.join(", "));
+    }
+    // A damaged file would replace the good backup on the next save; keep a copy of it instead
+    if recovered || !rejected.is_empty() {
+        backup_config(path);
+    }
+    (config, recovered || version < CONFIG_VERSION || !rejected.is_empty())
+}
 
-    pub fn ensure_inbox_folder(&self) -> Result<PathBuf, String> {
-        let config = self.get();
-        let path = PathBuf::from(&config.inbox_path);
-        if !path.exists() {
-            std::fs::create_dir_all(&path).map_err(|e| {
-                format!("Failed to create inbox folder '{}': {}", config.inbox_path, e)
-            })?;
+/// Apply each field over `base`, skipping the ones that don't deserialize.
+/// Returns the merged config and the names of the rejected fields.
+pub fn merge_fields(base: &AppConfig, fields: Map<String, Value>) -> (AppConfig, Vec<String>) {
+    let mut merged = match serde_json::to_value(base) {
+        Ok(Value::Object(base)) => base,
+        _ => return (base.clone(), Vec::new()),
+    };
+    let mut rejected = Vec::new();
+    for (key, value) in fields {
+        if !merged.contains_key(&key) {
+            log::debug!("Dropping unknown config field '{}'", key);
+            continue;
         }
-        Ok(path)
+        let previous = merged.insert(key.clone(), value);
+        if serde_json::from_value::<AppConfig>(Value::Object(merged.clone())).is_err() {
+            if let Some(previous) = previous {
+                merged.insert(key.clone(), previous);
+            }
+            rejected.push(key);
+        }
+    }
+    let config = serde_json::from_value(Value::Object(merged)).unwrap_or_else(|_| base.clone());
+    (config, rejected)
+}
+
+/// Apply the run's overrides and then the locked policy values over the user's config
+fn effective_config(
+    user_config: &AppConfig,
+    overrides: &Map<String, Value>,
+    policy: &Policy,
+) -> AppConfig {
+    let (config, _) = merge_fields(user_config, overrides.clone());
+    policy.enforce(&config)
+}
+
+/// Copy the fields selected by `keep` from `previous` into `config`
+pub fn restore_fields(
+    config: &AppConfig,
+    previous: &AppConfig,
+    keep: impl Fn(&str) -> bool,
+) -> AppConfig {
+    let Ok(Value::Object(previous)) = serde_json::to_value(previous) else {
+        return config.clone();
+    };
+    let fields = previous.into_iter().filter(|(key, _)| keep(key)).collect();
+    merge_fields(config, fields).0
+}
+
+/// Keep a copy of a config file that couldn't be read in full,
+/// e.g. `config.json.20240101-120000.bak`
+fn backup_config(path: &Path) {
+    let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".{}.bak", stamp));
+    match std::fs::copy(path, &backup) {
+        Ok(_) => log::warn!("Saved a copy of the previous config to {:?}", backup),
+        Err(e) => log::error!("Failed to back up config file: {}", e),
     }
 }
 
+/// Version 1 added `version` and `max_concurrent_uploads`
+fn migrate_v0_to_v1(fields: &mut Map<String, Value>) {
+    fields
+        .entry("max_concurrent_uploads")
+        .or_insert_with(|| Value::from(DEFAULT_MAX_CONCURRENT_UPLOADS));
+    fields.insert("version".to_string(), Value::from(1));
+}
+
+/// Version 2 added `watch_subfolders`; existing installs keep watching the top folder only
+fn migrate_v1_to_v2(fields: &mut Map<String, Value>) {
+    fields
+        .entry("watch_subfolders")
+        .or_insert(Value::Bool(false));
+    fields.insert("version".to_string(), Value::from(2));
+}
+
 /// Helper to get the "Subidos" subfolder path
 pub fn uploaded_subfolder(inbox_path: &str) -> PathBuf {
     PathBuf::from(inbox_path).join("Subidos")
@@ -118,3 +376,133 @@ mod dirs {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh folder for one test's config files
+    fn test_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("inbox-config-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn migrates_a_config_from_before_versioning() {
+        let dir = test_dir();
+        let path = dir.join("config.json");
+        // Written by the first release: no `version` and only these fields
+        std::fs::write(
+            &path,
+            r#"{
+  "server_url": "https://pb.example.com",
+  "web_url": "https://example.com",
+  "inbox_path": "/srv/inbox",
+  "delete_after_upload": false,
+  "auto_start": false
+}"#,
+        )
+        .unwrap();
+
+        let (config, changed) = load_config(&path, &AppConfig::default());
+        assert!(changed);
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.server_url, "https://pb.example.com");
+        assert_eq!(config.web_url, "https://example.com");
+        assert_eq!(config.inbox_path, "/srv/inbox");
+        assert!(!config.delete_after_upload);
+        assert!(!config.auto_start);
+        assert_eq!(config.max_concurrent_uploads, DEFAULT_MAX_CONCURRENT_UPLOADS);
+        // Existing installs keep watching the top folder only
+        assert!(!config.watch_subfolders);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn current_config_is_left_alone() {
+        let dir = test_dir();
+        let path = dir.join("config.json");
+        let saved = AppConfig {
+            inbox_path: "/srv/inbox".to_string(),
+            ..AppConfig::default()
+        };
+        std::fs::write(&path, serde_json::to_vec(&saved).unwrap()).unwrap();
+
+        let (config, changed) = load_config(&path, &AppConfig::default());
+        assert!(!changed);
+        assert_eq!(config.inbox_path, "/srv/inbox");
+        assert!(config.watch_subfolders);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn bad_field_is_rejected_without_resetting_the_others() {
+        let fields = serde_json::json!({
+            "inbox_path": "/srv/inbox",
+            "delete_after_upload": false,
+            "max_concurrent_uploads": "many",
+            "auto_start": false,
+            "unknown_field": true,
+        });
+        let Value::Object(fields) = fields else {
+            unreachable!()
+        };
+
+        let (config, rejected) = merge_fields(&AppConfig::default(), fields);
+        assert_eq!(rejected, vec!["max_concurrent_uploads".to_string()]);
+        assert_eq!(config.inbox_path, "/srv/inbox");
+        assert!(!config.delete_after_upload);
+        assert!(!config.auto_start);
+        assert_eq!(config.max_concurrent_uploads, DEFAULT_MAX_CONCURRENT_UPLOADS);
+    }
+
+    #[test]
+    fn bad_field_in_file_is_backed_up() {
+        let dir = test_dir();
+        let path = dir.join("config.json");
+        std::fs::write(
+            &path,
+            r#"{"version": 2, "inbox_path": "/srv/inbox", "auto_start": "sometimes"}"#,
+        )
+        .unwrap();
+
+        let (config, changed) = load_config(&path, &AppConfig::default());
+        assert!(changed);
+        assert_eq!(config.inbox_path, "/srv/inbox");
+        assert!(config.auto_start);
+        let backups = std::fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".bak"))
+            .count();
+        assert_eq!(backups, 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn damaged_file_is_recovered_from_backup_and_kept() {
+        let dir = test_dir();
+        let path = dir.join("config.json");
+        std::fs::write(&path, r#"{"inbox_path": "/srv/in"#).unwrap();
+        std::fs::write(
+            storage::backup_path(&path),
+            r#"{"version": 2, "inbox_path": "/srv/inbox"}"#,
+        )
+        .unwrap();
+
+        let (config, changed) = load_config(&path, &AppConfig::default());
+        assert!(changed);
+        assert_eq!(config.inbox_path, "/srv/inbox");
+        let kept: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| *p != storage::backup_path(&path))
+            .filter(|p| p.to_string_lossy().ends_with(".bak"))
+            .collect();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(std::fs::read(&kept[0]).unwrap(), std::fs::read(&path).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
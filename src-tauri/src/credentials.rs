@@ -0,0 +1,301 @@
+use crate::error::AppError;
+use crate::storage;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use std::path::{Path, PathBuf};
+
+/// Service name under which the session is saved in the OS secret store
+const KEYRING_SERVICE: &str = "com.inmobiliaria.inbox";
+const KEYRING_USER: &str = "session";
+
+const ENCRYPTED_FILE_NAME: &str = "credentials.enc";
+const KEY_FILE_NAME: &str = "credentials.key";
+
+/// AES-GCM nonce length in bytes
+const NONCE_LEN: usize = 12;
+
+/// A place where the serialized session can be kept between runs
+pub trait CredentialBackend: Send + Sync {
+    /// Short name used in logs
+    fn name(&self) -> &'static str;
+    /// Read the stored secret, `None` if nothing is stored
+    fn load(&self) -> Result<Option<String>, AppError>;
+    fn store(&self, secret: &str) -> Result<(), AppError>;
+    /// Remove the stored secret; succeeds if there was none
+    fn clear(&self) -> Result<(), AppError>;
+}
+
+/// OS secret store: Keychain on macOS, Credential Manager on Windows,
+/// Secret Service (GNOME Keyring, KWallet) on Linux
+pub struct KeyringBackend {
+    entry: keyring::Entry,
+}
+
+impl KeyringBackend {
+    /// Open the keyring entry and make sure the secret store actually answers
+    pub fn open() -> Result<Self, AppError> {
+        let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER)
+            .map_err(|e| AppError::Io(format!("Keyring unavailable: {}", e)))?;
+        match entry.get_password() {
+            Ok(_) | Err(keyring::Error::NoEntry) => Ok(Self { entry }),
+            Err(e) => Err(AppError::Io(format!("Keyring unavailable: {}", e))),
+        }
+    }
+}
+
+impl CredentialBackend for KeyringBackend {
+    fn name(&self) -> &'static str {
+        "keyring"
+    }
+
+    fn load(&self) -> Result<Option<String>, AppError> {
+        match self.entry.get_password() {
+            Ok(secret) => Ok(Some(secret)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(AppError::Io(format!("Failed to read keyring: {}", e))),
+        }
+    }
+
+    fn store(&self, secret: &str) -> Result<(), AppError> {
+        self.entry
+            .set_password(secret)
+            .map_err(|e| AppError::Io(format!("Failed to write keyring: {}", e)))
+    }
+
+    fn clear(&self) -> Result<(), AppError> {
+        match self.entry.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(AppError::Io(format!("Failed to clear keyring: {}", e))),
+        }
+    }
+}
+
+/// Fallback for systems without a usable secret store: AES-256-GCM encrypted file with a
+/// random key kept in a separate file, both readable by the current user only (0600)
+pub struct EncryptedFileBackend {
+    path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl EncryptedFileBackend {
+    pub fn new(app_data_dir: &Path) -> Self {
+        Self {
+            path: app_data_dir.join(ENCRYPTED_FILE_NAME),
+            key_path: app_data_dir.join(KEY_FILE_NAME),
+        }
+    }
+
+    /// Load the encryption key, creating it on first use
+    fn key(&self) -> Result<Key<Aes256Gcm>, AppError> {
+        let stored = storage::load_with_backup(&self.key_path, |bytes| {
+            if bytes.len() == 32 {
+                Ok(*Key::<Aes256Gcm>::from_slice(bytes))
+            } else {
+                Err("unexpected key length")
+            }
+        });
+        if let Some((key, _)) = stored {
+            return Ok(key);
+        }
+        if self.key_path.exists() {
+            log::warn!("Credentials key is unreadable, generating a new one");
+        }
+        let key = Aes256Gcm::generate_key(OsRng);
+        storage::write_atomic_private(&self.key_path, key.as_slice())
+            .map_err(|e| AppError::Io(format!("Failed to write credentials key: {}", e)))?;
+        Ok(key)
+    }
+}
+
+impl CredentialBackend for EncryptedFileBackend {
+    fn name(&self) -> &'static str {
+        "encrypted file"
+    }
+
+    fn load(&self) -> Result<Option<String>, AppError> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let cipher = Aes256Gcm::new(&self.key()?);
+        let decrypt = |bytes: &[u8]| -> Result<String, &'static str> {
+            if bytes.len() <= NONCE_LEN {
+                return Err("file is truncated");
+            }
+            let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+            let plaintext = cipher
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .map_err(|_| "decryption failed")?;
+            String::from_utf8(plaintext).map_err(|_| "invalid UTF-8")
+        };
+        storage::load_with_backup(&self.path, decrypt)
+            .map(|(secret, _)| Some(secret))
+            .ok_or_else(|| AppError::Io("Failed to decrypt credentials".to_string()))
+    }
+
+    fn store(&self, secret: &str) -> Result<(), AppError> {
+        let cipher = Aes256Gcm::new(&self.key()?);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, secret.as_bytes())
+            .map_err(|_| AppError::Io("Failed to encrypt credentials".to_string()))?;
+        let mut bytes = nonce.to_vec();
+        bytes.extend_from_slice(&ciphertext);
+        storage::write_atomic_private(&self.path, &bytes)
+            .map_err(|e| AppError::Io(format!("Failed to write credentials: {}", e)))
+    }
+
+    fn clear(&self) -> Result<(), AppError> {
+        // The backup holds the same token, so it has to go too
+        storage::remove_with_backup(&self.path)
+            .map_err(|e| AppError::Io(format!("Failed to remove credentials: {}", e)))
+    }
+}
+
+/// Pick the OS secret store when it works, otherwise the encrypted file. A session left in the
+/// encrypted file while the secret store was unavailable is moved into it.
+pub fn select_backend(app_data_dir: &Path) -> Box<dyn CredentialBackend> {
+    match KeyringBackend::open() {
+        Ok(backend) => {
+            move_secret(&EncryptedFileBackend::new(app_data_dir), &backend);
+            Box::new(backend)
+        }
+        Err(e) => {
+            log::warn!("{} — using encrypted file for credentials", e);
+            Box::new(EncryptedFileBackend::new(app_data_dir))
+        }
+    }
+}
+
+/// Move the secret in `from` to `to`. One already in `to` is newer and is kept. `from` is only
+/// cleared once `to` holds a secret, so a failure never loses the session.
+fn move_secret(from: &dyn CredentialBackend, to: &dyn CredentialBackend) {
+    let secret = match from.load() {
+        Ok(Some(secret)) => secret,
+        Ok(None) => return,
+        Err(e) => {
+            log::warn!("Leaving credentials in {}: {}", from.name(), e);
+            return;
+        }
+    };
+    match to.load() {
+        Ok(Some(_)) => log::info!("Dropping older credentials from {}", from.name()),
+        Ok(None) => {
+            if let Err(e) = to.store(&secret) {
+                log::error!("Failed to move credentials to {}: {}", to.name(), e);
+                return;
+            }
+            log::info!("Moved credentials from {} to {}", from.name(), to.name());
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    }
+    if let Err(e) = from.clear() {
+        log::error!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh folder for one test's credential files
+    fn test_dir() -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("inbox-credentials-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn encrypted_file_round_trip() {
+        let dir = test_dir();
+        let backend = EncryptedFileBackend::new(&dir);
+        assert_eq!(backend.load().unwrap(), None);
+
+        backend.store(r#"{"token":"secreto"}"#).unwrap();
+        assert_eq!(
+            backend.load().unwrap().as_deref(),
+            Some(r#"{"token":"secreto"}"#)
+        );
+        // Only the ciphertext is on disk
+        let bytes = std::fs::read(dir.join(ENCRYPTED_FILE_NAME)).unwrap();
+        assert!(!String::from_utf8_lossy(&bytes).contains("secreto"));
+
+        backend.clear().unwrap();
+        assert_eq!(backend.load().unwrap(), None);
+        backend.clear().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wrong_key_fails_to_load() {
+        let dir = test_dir();
+        let backend = EncryptedFileBackend::new(&dir);
+        backend.store("secreto").unwrap();
+
+        std::fs::write(dir.join(KEY_FILE_NAME), [7u8; 32]).unwrap();
+        assert!(backend.load().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_file_falls_back_to_the_previous_session() {
+        let dir = test_dir();
+        let backend = EncryptedFileBackend::new(&dir);
+        backend.store("anterior").unwrap();
+        backend.store("actual").unwrap();
+
+        let path = dir.join(ENCRYPTED_FILE_NAME);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(backend.load().unwrap().as_deref(), Some("anterior"));
+
+        // Nothing readable left
+        std::fs::write(&path, b"short").unwrap();
+        std::fs::write(storage::backup_path(&path), b"short").unwrap();
+        assert!(backend.load().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn encrypted_file_and_key_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = test_dir();
+        EncryptedFileBackend::new(&dir).store("secreto").unwrap();
+
+        for name in [ENCRYPTED_FILE_NAME, KEY_FILE_NAME] {
+            let mode = std::fs::metadata(dir.join(name))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600, "{}", name);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn session_is_moved_to_the_working_backend() {
+        let (from_dir, to_dir) = (test_dir(), test_dir());
+        let from = EncryptedFileBackend::new(&from_dir);
+        let to = EncryptedFileBackend::new(&to_dir);
+
+        from.store("secreto").unwrap();
+        move_secret(&from, &to);
+        assert_eq!(from.load().unwrap(), None);
+        assert_eq!(to.load().unwrap().as_deref(), Some("secreto"));
+
+        // A session already in the target is newer and stays
+        from.store("viejo").unwrap();
+        move_secret(&from, &to);
+        assert_eq!(from.load().unwrap(), None);
+        assert_eq!(to.load().unwrap().as_deref(), Some("secreto"));
+
+        std::fs::remove_dir_all(&from_dir).unwrap();
+        std::fs::remove_dir_all(&to_dir).unwrap();
+    }
+}
@@ -0,0 +1,357 @@
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+/// Upper bound for a server-provided `Retry-After` delay
+const MAX_RETRY_AFTER_SECS: u64 = 3600;
+
+/// A single field rejected by PocketBase (or by local validation)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    pub message: String,
+}
+
+/// Errors from talking to PocketBase or reading local files
+#[derive(Debug, Clone)]
+pub enum AppError {
+    /// The server could not be reached (DNS, refused connection, TLS...)
+    Network(String),
+    /// The request took too long
+    Timeout,
+    /// No stored session — the user never logged in or logged out
+    NotAuthenticated,
+    /// Wrong email or password on login
+    InvalidCredentials,
+    /// The token was rejected (401)
+    AuthExpired,
+    /// The user is not allowed to create records (403)
+    Forbidden,
+    /// The server refused the body size (413)
+    PayloadTooLarge,
+    /// PocketBase rejected the record (400) with per-field details
+    Validation {
+        message: String,
+        fields: Vec<FieldError>,
+    },
+    /// Rate limited or temporarily down (429/503), possibly with `Retry-After`
+    Unavailable {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+    /// Any other unexpected HTTP status
+    Server { status: u16, message: String },
+    /// The response body could not be understood
+    InvalidResponse(String),
+    /// The file has no content
+    EmptyFile,
+    /// The file exceeds the upload limit
+    FileTooLarge { size: u64, max: u64 },
+    /// Reading or writing a local file failed
+    Io(String),
+}
+
+/// Coarse error category, exposed to the UI alongside the message
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Network,
+    Timeout,
+    Auth,
+    Forbidden,
+    PayloadTooLarge,
+    Validation,
+    Unavailable,
+    Server,
+    File,
+    Io,
+}
+
+#[derive(Debug, Deserialize)]
+struct PocketBaseError {
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    data: std::collections::HashMap<String, PocketBaseFieldError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PocketBaseFieldError {
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    message: String,
+}
+
+impl AppError {
+    /// Classify a transport-level failure from reqwest
+    pub fn from_reqwest(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            AppError::Timeout
+        } else {
+            AppError::Network(err.to_string())
+        }
+    }
+
+    /// Build an error from a non-success PocketBase response
+    pub async fn from_response(response: Response) -> Self {
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_retry_after);
+        let body = response.text().await.unwrap_or_default();
+
+        match status {
+            StatusCode::UNAUTHORIZED => AppError::AuthExpired,
+            StatusCode::FORBIDDEN => AppError::Forbidden,
+            StatusCode::PAYLOAD_TOO_LARGE => AppError::PayloadTooLarge,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                AppError::Unavailable {
+                    status: status.as_u16(),
+                    retry_after,
+                }
+            }
+            StatusCode::BAD_REQUEST => {
+                let parsed: Option<PocketBaseError> = serde_json::from_str(&body).ok();
+                let (message, fields) = match parsed {
+                    Some(err) => {
+                        let mut fields: Vec<FieldError> = err
+                            .data
+                            .into_iter()
+                            .map(|(field, e)| FieldError {
+                                field,
+                                code: e.code,
+                                message: e.message,
+                            })
+                            .collect();
+                        fields.sort_by(|a, b| a.field.cmp(&b.field));
+                        (err.message, fields)
+                    }
+                    None => (body, Vec::new()),
+                };
+                AppError::Validation { message, fields }
+            }
+            _ => AppError::Server {
+                status: status.as_u16(),
+                message: body,
+            },
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            AppError::Network(_) => ErrorKind::Network,
+            AppError::Timeout => ErrorKind::Timeout,
+            AppError::NotAuthenticated | AppError::InvalidCredentials | AppError::AuthExpired => {
+                ErrorKind::Auth
+            }
+            AppError::Forbidden => ErrorKind::Forbidden,
+            AppError::PayloadTooLarge => ErrorKind::PayloadTooLarge,
+            AppError::Validation { .. } => ErrorKind::Validation,
+            AppError::Unavailable { .. } => ErrorKind::Unavailable,
+            AppError::Server { .. } | AppError::InvalidResponse(_) => ErrorKind::Server,
+            AppError::EmptyFile | AppError::FileTooLarge { .. } => ErrorKind::File,
+            AppError::Io(_) => ErrorKind::Io,
+        }
+    }
+
+    /// Whether trying the same request again later can succeed. An expired session isn't:
+    /// it needs a new token or the user logging in again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AppError::Network(_)
+            | AppError::Timeout
+            | AppError::Unavailable { .. }
+            | AppError::Io(_) => true,
+            AppError::Server { status, .. } => *status >= 500,
+            _ => false,
+        }
+    }
+
+    /// Whether the failure points at connectivity, so the server health should be re-checked
+    pub fn is_connectivity(&self) -> bool {
+        matches!(
+            self,
+            AppError::Network(_) | AppError::Timeout | AppError::Unavailable { .. }
+        )
+    }
+
+    /// Delay requested by the server before the next attempt
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            AppError::Unavailable { retry_after, .. } => {
+                retry_after.map(|d| d.min(Duration::from_secs(MAX_RETRY_AFTER_SECS)))
+            }
+            _ => None,
+        }
+    }
+
+    /// User-facing message in Spanish
+    pub fn user_message(&self) -> String {
+        match self {
+            AppError::Network(_) => "Error de conexión — verificá tu internet".to_string(),
+            AppError::Timeout => {
+                "Tiempo de espera agotado — conexión lenta o servidor no responde".to_string()
+            }
+            AppError::NotAuthenticated => "No hay una sesión iniciada".to_string(),
+            AppError::InvalidCredentials => "Email o contraseña incorrectos".to_string(),
+            AppError::AuthExpired => {
+                "La sesión venció — cerrá sesión y volvé a ingresar".to_string()
+            }
+            AppError::Forbidden => "Tu usuario no tiene permiso para subir archivos".to_string(),
+            AppError::PayloadTooLarge => {
+                "El servidor rechazó el archivo por ser muy grande".to_string()
+            }
+            AppError::Validation { message, fields } => {
+                if fields.is_empty() {
+                    format!("El servidor rechazó el archivo: {}", message)
+                } else {
+                    let details: Vec<String> = fields
+                        .iter()
+                        .map(|f| format!("{}: {}", f.field, f.message))
+                        .collect();
+                    format!("El servidor rechazó el archivo ({})", details.join(", "))
+                }
+            }
+            AppError::Unavailable { .. } => {
+                "El servidor está ocupado — se reintentará más tarde".to_string()
+            }
+            AppError::Server { status, .. } => format!("Error del servidor ({})", status),
+            AppError::InvalidResponse(_) => "Respuesta inesperada del servidor".to_string(),
+            AppError::EmptyFile => "Archivo vacío".to_string(),
+            AppError::FileTooLarge { size, max } => format!(
+                "Archivo demasiado grande ({:.0} MB, máx {:.0} MB)",
+                *size as f64 / 1_048_576.0,
+                *max as f64 / 1_048_576.0
+            ),
+            AppError::Io(_) => "No se pudo leer el archivo".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Network(e) => write!(f, "Connection error: {}", e),
+            AppError::Timeout => write!(f, "Request timed out"),
+            AppError::NotAuthenticated => write!(f, "Not authenticated"),
+            AppError::InvalidCredentials => write!(f, "Invalid email or password"),
+            AppError::AuthExpired => write!(f, "Token rejected (401)"),
+            AppError::Forbidden => write!(f, "Forbidden (403)"),
+            AppError::PayloadTooLarge => write!(f, "Payload too large (413)"),
+            AppError::Validation { message, fields } => {
+                write!(f, "Validation failed: {}", message)?;
+                for field in fields {
+                    write!(f, "; {}: {}", field.field, field.message)?;
+                }
+                Ok(())
+            }
+            AppError::Unavailable {
+                status,
+                retry_after,
+            } => match retry_after {
+                Some(d) => write!(f, "Server unavailable ({}), retry after {}s", status, d.as_secs()),
+                None => write!(f, "Server unavailable ({})", status),
+            },
+            AppError::Server { status, message } => write!(f, "Server error ({}): {}", status, message),
+            AppError::InvalidResponse(e) => write!(f, "Failed to parse response: {}", e),
+            AppError::EmptyFile => write!(f, "File is empty"),
+            AppError::FileTooLarge { size, max } => {
+                write!(f, "File too large ({} bytes, max {})", size, max)
+            }
+            AppError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+/// Commands return plain strings to the UI, rendered in Spanish
+impl From<AppError> for String {
+    fn from(err: AppError) -> Self {
+        err.user_message()
+    }
+}
+
+/// Error returned by commands that can point at specific settings fields
+#[derive(Debug, Serialize)]
+pub struct CommandError {
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+impl CommandError {
+    pub fn invalid(fields: Vec<FieldError>) -> Self {
+        Self {
+            message: "Revisá los datos marcados".to_string(),
+            fields,
+        }
+    }
+}
+
+impl From<String> for CommandError {
+    fn from(message: String) -> Self {
+        Self {
+            message,
+            fields: Vec::new(),
+        }
+    }
+}
+
+impl From<AppError> for CommandError {
+    fn from(err: AppError) -> Self {
+        err.user_message().into()
+    }
+}
+
+/// Parse a `Retry-After` header given either as seconds or as an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (at.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_in_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_as_http_date() {
+        let at = chrono::Utc::now() + chrono::Duration::seconds(90);
+        let header = at.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        let wait = parse_retry_after(&header).unwrap();
+        assert!(wait > Duration::from_secs(80) && wait <= Duration::from_secs(90));
+    }
+
+    #[test]
+    fn expired_session_is_not_retried() {
+        assert!(!AppError::AuthExpired.is_retryable());
+        assert!(!AppError::NotAuthenticated.is_retryable());
+        assert!(AppError::Timeout.is_retryable());
+    }
+
+    #[test]
+    fn retry_after_in_the_past_or_invalid() {
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after("-5"), None);
+    }
+}
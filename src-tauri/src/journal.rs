@@ -0,0 +1,51 @@
+use crate::storage;
+use crate::uploader::{LeftBehind, QueueItem, RecentUpload};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+const JOURNAL_FILE_NAME: &str = "upload_queue.json";
+
+/// Snapshot of the upload queue as written to disk
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct JournalData {
+    #[serde(default)]
+    pub queue: Vec<QueueItem>,
+    #[serde(default)]
+    pub recent: Vec<RecentUpload>,
+    #[serde(default)]
+    pub left_behind: Vec<LeftBehind>,
+}
+
+/// Persistent journal of the upload queue, stored next to `config.json`
+pub struct QueueJournal {
+    path: PathBuf,
+}
+
+impl QueueJournal {
+    pub fn new(app_data_dir: &Path) -> Self {
+        Self {
+            path: app_data_dir.join(JOURNAL_FILE_NAME),
+        }
+    }
+
+    /// Load the last saved snapshot, or the previous one if it is damaged.
+    /// A missing or unreadable journal yields an empty one.
+    pub fn load(&self) -> JournalData {
+        let parsed = storage::load_with_backup(&self.path, |bytes| {
+            serde_json::from_slice::<JournalData>(bytes)
+        });
+        parsed.map(|(data, _)| data).unwrap_or_else(|| {
+            if self.path.exists() {
+                log::error!("Failed to load upload journal, starting empty");
+            }
+            JournalData::default()
+        })
+    }
+
+    /// Overwrite the journal with the given snapshot
+    pub fn save(&self, data: &JournalData) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(data).map_err(|e| e.to_string())?;
+        storage::write_atomic(&self.path, json.as_bytes())
+            .map_err(|e| format!("Failed to write upload journal: {}", e))
+    }
+}
@@ -1,21 +1,38 @@
 mod auth;
+mod bundle;
+mod cli;
 mod commands;
 mod config;
+mod credentials;
+mod error;
+mod journal;
+mod policy;
+mod services;
+mod storage;
 mod tray;
 mod uploader;
+mod validation;
 mod watcher;
 
+use cli::LaunchOptions;
 use commands::AppState;
 use config::ConfigManager;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use tauri::Manager;
+use journal::QueueJournal;
+use policy::Policy;
+use services::ServiceSupervisor;
+use std::sync::{Arc, Mutex};
+use tauri::{Emitter, Manager};
 use uploader::UploadManager;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
+    let LaunchOptions {
+        autostarted,
+        overrides,
+    } = LaunchOptions::from_env();
+
     tauri::Builder::default()
         .on_window_event(|window, event| {
             if window.label() == "settings" {
@@ -46,14 +63,20 @@ pub fn run() {
             commands::logout,
             commands::check_auth,
             commands::get_config,
+            commands::get_locked_fields,
             commands::save_config,
             commands::get_status,
             commands::open_inbox_folder,
             commands::select_folder,
             commands::set_autostart,
+            commands::export_settings,
+            commands::import_settings,
+            commands::preview_ignore_rules,
             commands::start_services_cmd,
+            commands::stop_services,
+            commands::restart_services,
         ])
-        .setup(|app| {
+        .setup(move |app| {
             let app_handle = app.handle().clone();
 
             // Initialize config manager
@@ -65,16 +88,27 @@ pub fn run() {
             // Initialize auth module with app data path
             auth::init(&app_data_dir);
 
-            let config_manager = ConfigManager::new(app_data_dir);
+            // Initialize upload manager, replaying the queue journal from the last run
+            let upload_manager = Arc::new(UploadManager::new(QueueJournal::new(&app_data_dir)));
+            upload_manager.start_journal_writer();
+
+            let config_manager =
+                ConfigManager::new(app_data_dir, Policy::load(), overrides);
 
-            // Initialize upload manager
-            let upload_manager = Arc::new(UploadManager::new());
+            // Point the user at settings that no longer make sense, e.g. a deleted inbox drive.
+            // Fields locked by the policy or an override can't be fixed by the user.
+            let config_errors: Vec<_> = validation::validate(&config_manager.get())
+                .into_iter()
+                .inspect(|e| log::warn!("Invalid config value for {}: {}", e.field, e.message))
+                .filter(|e| !config_manager.is_locked(&e.field))
+                .collect();
 
             // Store app state
             app.manage(AppState {
                 config_manager,
                 upload_manager: upload_manager.clone(),
-                services_running: AtomicBool::new(false),
+                services: ServiceSupervisor::new(upload_manager.clone()),
+                config_errors: Mutex::new(config_errors),
             });
 
             // Create the tray icon
@@ -105,7 +139,13 @@ pub fn run() {
                     "settings" => {
                         show_settings_window(&app_handle_menu);
                     }
+                    "relogin" => {
+                        show_settings_window(&app_handle_menu);
+                        let _ = app_handle_menu.emit("show-login", ());
+                    }
                     "quit" => {
+                        // Changes still waiting for the journal writer
+                        app.state::<AppState>().upload_manager.flush_journal();
                         app.exit(0);
                     }
                     _ => {}
@@ -119,24 +159,41 @@ pub fn run() {
                 let state = app_handle_setup.state::<AppState>();
                 let config = state.config_manager.get();
 
-                // Check if we have stored credentials
-                let has_auth = if !config.server_url.is_empty() {
-                    auth::check_auth(&config.server_url).await.is_ok()
+                // Check if we have stored credentials and the session is still good
+                let session = if !config.server_url.is_empty() {
+                    auth::check_auth(&config.server_url).await.state
                 } else {
-                    false
+                    auth::SessionState::LoggedOut
                 };
 
-                if has_auth {
+                if session.is_usable() {
                     log::info!("Authenticated, starting services...");
-                    let state = app_handle_setup.state::<AppState>();
-                    state.services_running.store(true, Ordering::SeqCst);
                     start_services(&app_handle_setup, upload_manager_setup).await;
+                    if !state.config_errors.lock().unwrap().is_empty() && !autostarted {
+                        show_settings_window(&app_handle_setup);
+                    }
+                } else if autostarted {
+                    // Started with the system: don't pop up a window, just let the user know
+                    log::info!("Not authenticated ({:?}), waiting for login...", session);
+                    notify(
+                        &app_handle_setup,
+                        "Iniciá sesión desde el ícono de la bandeja para empezar a subir archivos",
+                    );
                 } else {
-                    log::info!("Not authenticated, showing settings window...");
+                    log::info!("Not authenticated ({:?}), showing settings window...", session);
                     show_settings_window(&app_handle_setup);
                 }
             });
 
+            // Refresh the auth token ahead of its expiry
+            let app_handle_auth = app_handle.clone();
+            tauri::async_runtime::spawn(async move {
+                auth::keep_session_fresh(|| {
+                    app_handle_auth.state::<AppState>().config_manager.get().server_url
+                })
+                .await;
+            });
+
             // Periodic tray update
             let app_handle_tray = app_handle.clone();
             let upload_manager_tray = upload_manager.clone();
@@ -144,15 +201,33 @@ pub fn run() {
                 loop {
                     tokio::time::sleep(std::time::Duration::from_secs(3)).await;
                     if let Some(tray) = app_handle_tray.tray_by_id("main-tray") {
+                        let state = app_handle_tray.state::<AppState>();
                         let _ = tray::update_tray(
                             &app_handle_tray,
                             &tray,
                             &upload_manager_tray,
+                            &state.services,
                         );
                     }
                 }
             });
 
+            // Push upload progress to the settings window while files are being sent
+            let app_handle_progress = app_handle.clone();
+            let upload_manager_progress = upload_manager.clone();
+            tauri::async_runtime::spawn(async move {
+                let mut was_uploading = false;
+                loop {
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    let progress = upload_manager_progress.get_progress();
+                    // Emit once more after the last upload ends so the UI can clear it
+                    if !progress.is_empty() || was_uploading {
+                        let _ = app_handle_progress.emit("upload-progress", &progress);
+                    }
+                    was_uploading = !progress.is_empty();
+                }
+            });
+
             Ok(())
         })
         .run(tauri::generate_context!())
@@ -181,98 +256,111 @@ fn show_settings_window(app: &tauri::AppHandle) {
 
 pub async fn start_services(app: &tauri::AppHandle, upload_manager: Arc<UploadManager>) {
     let state = app.state::<AppState>();
+    let Some(lifetime) = state.services.begin() else {
+        // Already running
+        return;
+    };
     let config = state.config_manager.get();
 
-    // Ensure inbox folder exists
-    match state.config_manager.ensure_inbox_folder() {
-        Ok(inbox_path) => {
-            log::info!("Inbox folder ready: {:?}", inbox_path);
+    // Watch the inbox and start uploading; both are restarted on config changes.
+    // The watcher creates a missing inbox folder, or waits for its drive or share.
+    state.services.start_watcher(&config);
+    state.services.start_worker(&config);
 
-            // Scan existing files first
-            let existing = watcher::scan_existing_files(&inbox_path);
-            for file in existing {
-                upload_manager.enqueue(file);
+    // Notification watcher: check for new successful or failed uploads periodically
+    let app_handle = app.clone();
+    let upload_manager_notif = upload_manager.clone();
+    tauri::async_runtime::spawn(async move {
+        // Entries restored from the journal were already notified in a previous run
+        let restored = upload_manager_notif.get_recent();
+        let count_status = |recent: &[uploader::RecentUpload], status: uploader::UploadStatus| {
+            recent.iter().filter(|r| r.status == status).count()
+        };
+        let mut last_success_count = count_status(&restored, uploader::UploadStatus::Success);
+        let mut last_failed_count = count_status(&restored, uploader::UploadStatus::Failed);
+        let mut last_imports = upload_manager_notif.history_imports();
+        let mut was_needs_login = false;
+        loop {
+            tokio::select! {
+                _ = lifetime.cancelled() => break,
+                _ = tokio::time::sleep(std::time::Duration::from_secs(2)) => {}
             }
+            let recent = upload_manager_notif.get_recent();
+            // Imported entries were notified on the computer that uploaded them
+            let imports = upload_manager_notif.history_imports();
+            if imports != last_imports {
+                last_imports = imports;
+                last_success_count = count_status(&recent, uploader::UploadStatus::Success);
+                last_failed_count = count_status(&recent, uploader::UploadStatus::Failed);
+            }
+            let current_success = count_status(&recent, uploader::UploadStatus::Success);
 
-            // Start file watcher
-            let upload_manager_watcher = upload_manager.clone();
-            let inbox_path_watcher = inbox_path.clone();
-            std::thread::spawn(move || {
-                match watcher::start_watching(&inbox_path_watcher) {
-                    Ok((rx, _debouncer)) => {
-                        log::info!("File watcher started successfully");
-                        // Keep receiving file events
-                        while let Ok(path) = rx.recv() {
-                            upload_manager_watcher.enqueue(path);
-                        }
-                    }
-                    Err(e) => {
-                        log::error!("Failed to start file watcher: {}", e);
-                    }
-                }
-            });
-
-            // Start upload worker
-            let server_url = config.server_url.clone();
-            let delete_after = config.delete_after_upload;
-            let inbox_str = config.inbox_path.clone();
-            let upload_manager_worker = upload_manager.clone();
-
-            // Send notification for successful uploads
-            let app_handle = app.clone();
-            let upload_manager_notif = upload_manager.clone();
-            tauri::async_runtime::spawn(async move {
-                upload_manager_worker
-                    .start_worker(server_url, delete_after, inbox_str)
-                    .await;
-            });
-
-            // Notification watcher: check for new successful uploads periodically
-            tauri::async_runtime::spawn(async move {
-                let mut last_success_count = 0usize;
-                loop {
-                    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-                    let recent = upload_manager_notif.get_recent();
-                    let current_success = recent
+            if current_success > last_success_count {
+                let new_count = current_success - last_success_count;
+                let body = if new_count == 1 {
+                    let name = recent
                         .iter()
-                        .filter(|r| r.status == uploader::UploadStatus::Success)
-                        .count();
+                        .find(|r| r.status == uploader::UploadStatus::Success)
+                        .map(|r| r.name.clone())
+                        .unwrap_or_default();
+                    format!("{} subido exitosamente", name)
+                } else {
+                    format!("{} archivos subidos exitosamente", new_count)
+                };
 
-                    if current_success > last_success_count {
-                        let new_count = current_success - last_success_count;
-                        let body = if new_count == 1 {
-                            let name = recent
-                                .iter()
-                                .find(|r| r.status == uploader::UploadStatus::Success)
-                                .map(|r| r.name.clone())
-                                .unwrap_or_default();
-                            format!("{} subido exitosamente", name)
-                        } else {
-                            format!("{} archivos subidos exitosamente", new_count)
-                        };
+                notify(&app_handle, &body);
+            }
+            last_success_count = current_success;
+
+            // Failures that won't be retried; the message depends on the error kind
+            let current_failed = count_status(&recent, uploader::UploadStatus::Failed);
 
-                        if let Ok(true) =
-                            tauri_plugin_notification::NotificationExt::notification(
-                                &app_handle,
-                            )
-                            .permission_state()
-                            .map(|s| s == tauri_plugin_notification::PermissionState::Granted)
-                        {
-                            let _ = tauri_plugin_notification::NotificationExt::notification(
-                                &app_handle,
-                            )
-                            .builder()
-                            .title("Inmobiliaria Inbox")
-                            .body(&body)
-                            .show();
+            if current_failed > last_failed_count {
+                if let Some(failed) = recent
+                    .iter()
+                    .find(|r| r.status == uploader::UploadStatus::Failed)
+                {
+                    let body = match failed.error_kind {
+                        Some(error::ErrorKind::Auth) => {
+                            "La sesión venció — volvé a iniciar sesión".to_string()
                         }
-                    }
-                    last_success_count = current_success;
+                        Some(error::ErrorKind::Forbidden) => {
+                            "Tu usuario no tiene permiso para subir archivos".to_string()
+                        }
+                        _ => format!(
+                            "No se pudo subir {}: {}",
+                            failed.name,
+                            failed.error.clone().unwrap_or_default()
+                        ),
+                    };
+                    notify(&app_handle, &body);
                 }
-            });
-        }
-        Err(e) => {
-            log::error!("Failed to create inbox folder: {}", e);
+            }
+            last_failed_count = current_failed;
+
+            // Uploads paused because the session could not be refreshed
+            let needs_login = upload_manager_notif.needs_login();
+            if needs_login && !was_needs_login {
+                notify(
+                    &app_handle,
+                    "La sesión venció — iniciá sesión para seguir subiendo archivos",
+                );
+            }
+            was_needs_login = needs_login;
         }
+    });
+}
+
+/// Show a system notification if the user granted permission
+fn notify(app: &tauri::AppHandle, body: &str) {
+    use tauri_plugin_notification::{NotificationExt, PermissionState};
+
+    if let Ok(PermissionState::Granted) = app.notification().permission_state() {
+        let _ = app
+            .notification()
+            .builder()
+            .title("Inmobiliaria Inbox")
+            .body(body)
+            .show();
     }
 }
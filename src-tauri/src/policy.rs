@@ -0,0 +1,121 @@
+use crate::config::{merge_fields, AppConfig};
+use crate::validation;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::path::PathBuf;
+
+/// Admin policy for managed installs, read from a system-wide location:
+///
+/// ```json
+/// {
+///   "defaults": { "inbox_path": "D:\\Inbox" },
+///   "locked": { "server_url": "https://pb.example.com", "delete_after_upload": false }
+/// }
+/// ```
+///
+/// `defaults` replace the built-in defaults; `locked` values always win and can't be changed
+/// from the settings window.
+#[derive(Debug, Default, Deserialize)]
+pub struct Policy {
+    #[serde(default)]
+    defaults: Map<String, Value>,
+    #[serde(default)]
+    locked: Map<String, Value>,
+}
+
+impl Policy {
+    /// Load the policy from the system location. No file means no policy.
+    pub fn load() -> Self {
+        let Some(path) = policy_path() else {
+            return Self::default();
+        };
+        if !path.exists() {
+            return Self::default();
+        }
+        let parsed = std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| serde_json::from_slice::<Policy>(&bytes).map_err(|e| e.to_string()));
+        match parsed {
+            Ok(mut policy) => {
+                policy.defaults.retain(|key, _| is_policy_field(key));
+                policy.locked.retain(|key, _| is_policy_field(key));
+                // A locked value can't be fixed by the user, so a bad one must not be applied
+                validation::drop_invalid_values(&mut policy.defaults, "policy default");
+                validation::drop_invalid_values(&mut policy.locked, "locked policy value");
+                log::info!(
+                    "Loaded policy from {:?} (locked: {})",
+                    path,
+                    policy.locked_fields().join(", ")
+                );
+                policy
+            }
+            Err(e) => {
+                log::error!("Failed to read policy file {:?}: {}", path, e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn is_locked(&self, field: &str) -> bool {
+        self.locked.contains_key(field)
+    }
+
+    pub fn locked_fields(&self) -> Vec<String> {
+        self.locked.keys().cloned().collect()
+    }
+
+    /// Built-in defaults with the policy defaults applied
+    pub fn defaults(&self) -> AppConfig {
+        let (config, rejected) = merge_fields(&AppConfig::default(), self.defaults.clone());
+        if !rejected.is_empty() {
+            log::warn!("Ignored invalid policy defaults: {}", rejected.join(", "));
+        }
+        config
+    }
+
+    /// Override the locked fields of `config` with the policy values
+    pub fn enforce(&self, config: &AppConfig) -> AppConfig {
+        let (config, rejected) = merge_fields(config, self.locked.clone());
+        if !rejected.is_empty() {
+            log::warn!("Ignored invalid locked policy values: {}", rejected.join(", "));
+        }
+        config
+    }
+}
+
+/// Whether a policy may set `key`; unknown keys are logged and dropped
+fn is_policy_field(key: &str) -> bool {
+    let known = match serde_json::to_value(AppConfig::default()) {
+        Ok(Value::Object(fields)) => fields.contains_key(key),
+        _ => false,
+    };
+    // The schema version belongs to the user's file
+    if !known || key == "version" {
+        log::warn!("Ignoring unknown policy field '{}'", key);
+        return false;
+    }
+    true
+}
+
+/// System-wide location of the policy file, writable by administrators only
+fn policy_path() -> Option<PathBuf> {
+    #[cfg(target_os = "linux")]
+    {
+        Some(PathBuf::from("/etc/inmobiliaria-inbox/policy.json"))
+    }
+    #[cfg(target_os = "macos")]
+    {
+        Some(PathBuf::from(
+            "/Library/Application Support/Inmobiliaria Inbox/policy.json",
+        ))
+    }
+    #[cfg(target_os = "windows")]
+    {
+        std::env::var_os("ProgramData")
+            .map(|dir| PathBuf::from(dir).join("Inmobiliaria Inbox").join("policy.json"))
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        None
+    }
+}
@@ -0,0 +1,633 @@
+use crate::config::{AppConfig, ConfigManager};
+use crate::uploader::UploadManager;
+use crate::watcher::{self, FolderId, IgnoreRules, ReadinessTracker};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::any::Any;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// How often the watcher thread checks whether it was asked to stop
+const WATCHER_STOP_POLL: Duration = Duration::from_millis(500);
+
+/// How often files waiting to finish arriving are checked
+const READINESS_POLL: Duration = Duration::from_millis(500);
+
+/// How often the watcher checks that the inbox folder is still the one being watched
+const INBOX_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often a missing inbox folder is looked for
+const INBOX_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the inbox is compared with the queue, for files whose events were missed
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Delay before restarting a failed service, doubled on each consecutive failure
+const RESTART_DELAY_BASE: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// A service that ran at least this long before failing starts its backoff over
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Stopped,
+    Running,
+    /// Failed and waiting for its next restart
+    Restarting,
+    /// Waiting for something outside the app, like the inbox folder coming back
+    Waiting,
+}
+
+/// State of a supervised background service, shown in `get_status`
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskHealth {
+    pub name: &'static str,
+    pub state: TaskState,
+    /// Restarts since the app started
+    pub restarts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error_at: Option<DateTime<Utc>>,
+}
+
+impl TaskHealth {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            state: TaskState::Stopped,
+            restarts: 0,
+            last_error: None,
+            last_error_at: None,
+        }
+    }
+
+    fn failed(&mut self, error: String) {
+        self.state = TaskState::Restarting;
+        self.restarts += 1;
+        self.last_error = Some(error);
+        self.last_error_at = Some(Utc::now());
+    }
+}
+
+/// Results of the periodic scan that looks for files the watcher missed
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReconcileReport {
+    pub last_run_at: Option<DateTime<Utc>>,
+    /// Files found by the last scan
+    pub last_recovered: usize,
+    /// Files found since the app started
+    pub total_recovered: usize,
+}
+
+/// Which background services have to be restarted after a config change
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ConfigChanges {
+    pub watcher: bool,
+    pub worker: bool,
+}
+
+impl ConfigChanges {
+    pub fn between(old: &AppConfig, new: &AppConfig) -> Self {
+        let watcher = old.inbox_path != new.inbox_path
+            || old.file_settle_secs != new.file_settle_secs
+            || old.watch_mode != new.watch_mode
+            || old.poll_interval_secs != new.poll_interval_secs
+            || old.ignore_patterns != new.ignore_patterns
+            || old.watch_subfolders != new.watch_subfolders;
+        // The worker also captures the inbox path, for the "Subidos" folder
+        let worker = old.inbox_path != new.inbox_path
+            || old.server_url != new.server_url
+            || old.delete_after_upload != new.delete_after_upload
+            || old.max_concurrent_uploads != new.max_concurrent_uploads;
+        Self { watcher, worker }
+    }
+}
+
+/// Owns the background services started after login: the file watcher, the upload worker
+/// and anything else tied to the session, which is cancelled through `lifetime` on stop
+pub struct ServiceSupervisor {
+    upload_manager: Arc<UploadManager>,
+    lifetime: Mutex<Option<CancellationToken>>,
+    watcher: Mutex<Option<CancellationToken>>,
+    worker: Mutex<Option<CancellationToken>>,
+    watcher_health: Arc<Mutex<TaskHealth>>,
+    worker_health: Arc<Mutex<TaskHealth>>,
+    reconcile: Arc<Mutex<ReconcileReport>>,
+}
+
+impl ServiceSupervisor {
+    pub fn new(upload_manager: Arc<UploadManager>) -> Self {
+        Self {
+            upload_manager,
+            lifetime: Mutex::new(None),
+            watcher: Mutex::new(None),
+            worker: Mutex::new(None),
+            watcher_health: Arc::new(Mutex::new(TaskHealth::new("watcher"))),
+            worker_health: Arc::new(Mutex::new(TaskHealth::new("worker"))),
+            reconcile: Arc::new(Mutex::new(ReconcileReport::default())),
+        }
+    }
+
+    /// Mark the services as running. Returns the token cancelled by `stop`,
+    /// or `None` if they were already running.
+    pub fn begin(&self) -> Option<CancellationToken> {
+        let mut lifetime = self.lifetime.lock().unwrap();
+        if lifetime.is_some() {
+            return None;
+        }
+        let token = CancellationToken::new();
+        *lifetime = Some(token.clone());
+        Some(token)
+    }
+
+    /// Stop every background service. Uploads in flight are interrupted and stay queued.
+    pub fn stop(&self) {
+        self.stop_watcher();
+        self.stop_worker();
+        if let Some(lifetime) = self.lifetime.lock().unwrap().take() {
+            lifetime.cancel();
+            log::info!("Background services stopped");
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.lifetime.lock().unwrap().is_some()
+    }
+
+    /// Token for a new service, cancelled along with the others on `stop`
+    fn service_token(&self) -> CancellationToken {
+        match self.lifetime.lock().unwrap().as_ref() {
+            Some(lifetime) => lifetime.child_token(),
+            None => CancellationToken::new(),
+        }
+    }
+
+    /// Whether the watcher is waiting for a missing inbox folder
+    pub fn inbox_unavailable(&self) -> bool {
+        self.watcher_health.lock().unwrap().state == TaskState::Waiting
+    }
+
+    pub fn reconcile_report(&self) -> ReconcileReport {
+        self.reconcile.lock().unwrap().clone()
+    }
+
+    /// Health of every supervised service, for the status view
+    pub fn health(&self) -> Vec<TaskHealth> {
+        vec![
+            self.watcher_health.lock().unwrap().clone(),
+            self.worker_health.lock().unwrap().clone(),
+        ]
+    }
+
+    /// Enqueue the files already in the inbox and watch it for new ones, once each file has
+    /// stayed unchanged for `file_settle_secs`. The watcher is restarted with backoff if it
+    /// fails or panics, and waits for the folder if it goes missing.
+    pub fn start_watcher(&self, config: &AppConfig) {
+        self.stop_watcher();
+
+        let cancel = self.service_token();
+        let stopped = cancel.clone();
+        let upload_manager = self.upload_manager.clone();
+        let config = config.clone();
+        let health = self.watcher_health.clone();
+        let reconcile = self.reconcile.clone();
+        health.lock().unwrap().state = TaskState::Running;
+        std::thread::spawn(move || {
+            let mut failures = 0;
+            loop {
+                let started = Instant::now();
+                let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                    watch_inbox(&config, &upload_manager, &health, &reconcile, &stopped)
+                }));
+                let error = match result {
+                    Ok(Ok(())) => "File watcher stopped unexpectedly".to_string(),
+                    Ok(Err(e)) => e,
+                    Err(panic) => panic_message(panic),
+                };
+                let Some(delay) = record_failure(&health, &stopped, &mut failures, started, error)
+                else {
+                    break;
+                };
+                if !sleep_unless_stopped(&stopped, delay) {
+                    break;
+                }
+                if !update_health(&health, &stopped, |h| h.state = TaskState::Running) {
+                    break;
+                }
+            }
+            log::info!("File watcher stopped: {:?}", config.inbox_path);
+        });
+        *self.watcher.lock().unwrap() = Some(cancel);
+    }
+
+    pub fn stop_watcher(&self) {
+        // Hold the health lock so a failing watcher can't report after being stopped
+        let mut health = self.watcher_health.lock().unwrap();
+        if let Some(cancel) = self.watcher.lock().unwrap().take() {
+            cancel.cancel();
+        }
+        health.state = TaskState::Stopped;
+    }
+
+    /// Start the upload worker with the settings from `config`.
+    /// The worker is restarted with backoff if it exits or panics.
+    pub fn start_worker(&self, config: &AppConfig) {
+        self.stop_worker();
+
+        let cancel = self.service_token();
+        let stopped = cancel.clone();
+        let upload_manager = self.upload_manager.clone();
+        let config = config.clone();
+        let health = self.worker_health.clone();
+        health.lock().unwrap().state = TaskState::Running;
+        tauri::async_runtime::spawn(async move {
+            let mut failures = 0;
+            loop {
+                let started = Instant::now();
+                let run = stopped.child_token();
+                // A separate task so a panic surfaces as a `JoinError` instead of killing us
+                let mut worker = tokio::spawn(upload_manager.clone().start_worker(
+                    config.server_url.clone(),
+                    config.delete_after_upload,
+                    config.inbox_path.clone(),
+                    config.max_concurrent_uploads,
+                    run.clone(),
+                ));
+                let result = tokio::select! {
+                    _ = stopped.cancelled() => {
+                        // Uploads in flight are cancelled with it and put their files back
+                        worker.abort();
+                        break;
+                    }
+                    result = &mut worker => result,
+                };
+                // This run's uploads end with it, so they don't go over the limit alongside
+                // the next run's
+                run.cancel();
+                let error = match result {
+                    Ok(()) => "Upload worker stopped unexpectedly".to_string(),
+                    Err(e) if e.is_panic() => panic_message(e.into_panic()),
+                    Err(e) => e.to_string(),
+                };
+                let Some(delay) = record_failure(&health, &stopped, &mut failures, started, error)
+                else {
+                    break;
+                };
+                tokio::select! {
+                    _ = stopped.cancelled() => break,
+                    _ = tokio::time::sleep(delay) => {}
+                }
+                if !update_health(&health, &stopped, |h| h.state = TaskState::Running) {
+                    break;
+                }
+            }
+            log::info!("Upload worker stopped");
+        });
+        *self.worker.lock().unwrap() = Some(cancel);
+    }
+
+    pub fn stop_worker(&self) {
+        let mut health = self.worker_health.lock().unwrap();
+        if let Some(cancel) = self.worker.lock().unwrap().take() {
+            cancel.cancel();
+        }
+        health.state = TaskState::Stopped;
+    }
+
+    /// Restart the services affected by a config change. Stopped services stay stopped.
+    pub fn apply_config(&self, old: &AppConfig, config_manager: &ConfigManager) {
+        let config = config_manager.get();
+        let changes = ConfigChanges::between(old, &config);
+
+        if changes.watcher && is_running(&self.watcher) {
+            log::info!("Inbox settings changed, restarting file watcher");
+            self.start_watcher(&config);
+        }
+        if changes.worker && is_running(&self.worker) {
+            log::info!("Upload settings changed, restarting upload worker");
+            self.start_worker(&config);
+        }
+    }
+}
+
+fn is_running(service: &Mutex<Option<CancellationToken>>) -> bool {
+    service.lock().unwrap().is_some()
+}
+
+/// Forward new files to the upload queue once they're ready, until `stopped` is cancelled.
+/// If the inbox folder disappears or is replaced, waits for it and watches it again.
+fn watch_inbox(
+    config: &AppConfig,
+    upload_manager: &UploadManager,
+    health: &Mutex<TaskHealth>,
+    reconcile: &Mutex<ReconcileReport>,
+    stopped: &CancellationToken,
+) -> Result<(), String> {
+    let inbox_path = Path::new(&config.inbox_path);
+    let mut pending = ReadinessTracker::new(config.file_settle());
+    let mut watched = None;
+    while wait_for_inbox(inbox_path, watched, health, stopped) {
+        // Taken before watching, so a folder swapped in meanwhile is noticed on the next check
+        watched = watcher::folder_id(inbox_path);
+        // Checked on every start, since a share may be mounted where a local folder was
+        let (rx, mut watch) = watcher::start_watching(
+            inbox_path,
+            config.watch_mode,
+            config.poll_interval(),
+            config.watch_subfolders,
+        )?;
+        log::info!("File watcher started successfully");
+        let mut rules = load_rules(config);
+
+        // Files may have arrived while nothing was watching, or still be copying. Files that
+        // failed for good or stayed after uploading are only picked up again once changed.
+        for file in watcher::scan_existing_files(inbox_path, &rules) {
+            if !pending.is_pending(&file) && !upload_manager.is_tracked(&file) {
+                pending.track(file);
+            }
+        }
+
+        let mut last_poll = Instant::now();
+        let mut last_check = Instant::now();
+        let mut last_reconcile = Instant::now();
+        while !stopped.is_cancelled() {
+            match rx.recv_timeout(WATCHER_STOP_POLL) {
+                Ok(path) if path.is_dir() => {
+                    // A new folder in the inbox gets its own watch, before it's scanned
+                    if path.parent() == Some(inbox_path) {
+                        watch.watch_subfolder(&path);
+                    }
+                    // The folder's files may not have been reported one by one
+                    for file in watcher::scan_folder(&path, &rules) {
+                        if !pending.is_pending(&file) && !upload_manager.is_tracked(&file) {
+                            pending.track(file);
+                        }
+                    }
+                }
+                Ok(path) if !rules.is_ignored(&path) => pending.track(path),
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err("File watcher channel closed".to_string());
+                }
+            }
+            if last_poll.elapsed() >= READINESS_POLL {
+                // A file may have been ignored by a rule added while it was pending
+                for path in pending.poll().into_iter().filter(|p| !rules.is_ignored(p)) {
+                    log::info!("New file ready: {:?}", path);
+                    upload_manager.enqueue(inbox_path, path);
+                }
+                last_poll = Instant::now();
+            }
+            // The OS watch goes quiet without an error when its folder is deleted, renamed
+            // or unmounted
+            if last_check.elapsed() >= INBOX_CHECK_INTERVAL {
+                if watcher::folder_id(inbox_path) != watched {
+                    log::warn!("Inbox folder {:?} was removed or replaced", inbox_path);
+                    break;
+                }
+                if rules.is_stale() {
+                    log::info!("{} changed, reloading ignore rules", watcher::IGNORE_FILE_NAME);
+                    rules = load_rules(config);
+                    // Files that are no longer ignored are picked up now
+                    for file in watcher::scan_existing_files(inbox_path, &rules) {
+                        if !pending.is_pending(&file) && !upload_manager.is_tracked(&file) {
+                            pending.track(file);
+                        }
+                    }
+                }
+                last_check = Instant::now();
+            }
+            if last_reconcile.elapsed() >= RECONCILE_INTERVAL {
+                reconcile_inbox(inbox_path, &rules, &mut pending, upload_manager, reconcile);
+                last_reconcile = Instant::now();
+            }
+        }
+        // Dropping the watch here stops watching the folder
+    }
+    Ok(())
+}
+
+/// Ignore rules for the inbox, as set in `config`
+fn load_rules(config: &AppConfig) -> IgnoreRules {
+    IgnoreRules::load(
+        Path::new(&config.inbox_path),
+        &config.ignore_patterns,
+        config.watch_subfolders,
+    )
+}
+
+/// Look for files in the inbox that nothing is handling, because the watcher dropped their
+/// events or they were removed from the queue, and wait for them like new files
+fn reconcile_inbox(
+    inbox_path: &Path,
+    rules: &IgnoreRules,
+    pending: &mut ReadinessTracker,
+    upload_manager: &UploadManager,
+    reconcile: &Mutex<ReconcileReport>,
+) {
+    let missed: Vec<PathBuf> = watcher::scan_existing_files(inbox_path, rules)
+        .into_iter()
+        .filter(|path| !pending.is_pending(path) && !upload_manager.is_tracked(path))
+        // Empty files are skipped until something is written to them
+        .filter(|path| std::fs::metadata(path).is_ok_and(|m| m.len() > 0))
+        .collect();
+    if !missed.is_empty() {
+        log::warn!("Reconciliation found {} file(s) missed by the watcher", missed.len());
+    }
+
+    let mut report = reconcile.lock().unwrap();
+    report.last_run_at = Some(Utc::now());
+    report.last_recovered = missed.len();
+    report.total_recovered += missed.len();
+    for path in missed {
+        log::info!("Recovered missed file: {:?}", path);
+        pending.track(path);
+    }
+}
+
+/// Make sure the inbox folder exists before watching it. A missing folder is created if
+/// `may_create_inbox` allows it; one on a drive or network share that is not connected is
+/// waited for, even if its empty mount point is there. Returns `false` if stopped while waiting.
+fn wait_for_inbox(
+    inbox_path: &Path,
+    watched: Option<FolderId>,
+    health: &Mutex<TaskHealth>,
+    stopped: &CancellationToken,
+) -> bool {
+    let mut waiting = false;
+    loop {
+        if stopped.is_cancelled() {
+            return false;
+        }
+        if inbox_path.is_dir() {
+            if waiting {
+                log::info!("Inbox folder {:?} is available again", inbox_path);
+                return update_health(health, stopped, |h| h.state = TaskState::Running);
+            }
+            return true;
+        }
+
+        if may_create_inbox(inbox_path, watched) {
+            match std::fs::create_dir(inbox_path) {
+                Ok(()) => {
+                    log::warn!("Inbox folder {:?} was missing, created it again", inbox_path);
+                    continue;
+                }
+                Err(e) => log::debug!("Could not create inbox folder {:?}: {}", inbox_path, e),
+            }
+        }
+
+        if !waiting {
+            log::warn!("Inbox folder {:?} is not available, waiting for it", inbox_path);
+            waiting = true;
+            let updated = update_health(health, stopped, |h| {
+                h.state = TaskState::Waiting;
+                h.last_error = Some(format!("Inbox folder {:?} is not available", inbox_path));
+                h.last_error_at = Some(Utc::now());
+            });
+            if !updated {
+                return false;
+            }
+        }
+        if !sleep_unless_stopped(stopped, INBOX_RETRY_INTERVAL) {
+            return false;
+        }
+    }
+}
+
+/// Whether a missing inbox folder can be created without hiding a drive or share that isn't
+/// connected. Once a folder was `watched`, its parent must still be on the same device. Before
+/// that, on first start or after the path changed, the parent must be a local folder that
+/// isn't a mount point, so the new folder can't end up in an empty mount point.
+fn may_create_inbox(inbox_path: &Path, watched: Option<FolderId>) -> bool {
+    let Some(parent) = inbox_path.parent().filter(|p| p.is_dir()) else {
+        return false;
+    };
+    match watched {
+        Some(id) => id.is_same_device(parent),
+        None => !watcher::is_mount_point(parent) && !watcher::is_network_path(parent),
+    }
+}
+
+/// Update a task's health unless it was stopped meanwhile, since a replacement task may
+/// already own the entry. Returns whether the task should keep going.
+fn update_health(
+    health: &Mutex<TaskHealth>,
+    stopped: &CancellationToken,
+    update: impl FnOnce(&mut TaskHealth),
+) -> bool {
+    let mut health = health.lock().unwrap();
+    if stopped.is_cancelled() {
+        return false;
+    }
+    update(&mut health);
+    true
+}
+
+/// Note a crash and return how long to wait before restarting, or `None` if stopped
+fn record_failure(
+    health: &Mutex<TaskHealth>,
+    stopped: &CancellationToken,
+    failures: &mut u32,
+    started: Instant,
+    error: String,
+) -> Option<Duration> {
+    // A task that ran fine for a while starts its backoff over
+    if started.elapsed() >= HEALTHY_RUN {
+        *failures = 0;
+    }
+    *failures += 1;
+    let delay = restart_delay(*failures);
+    let name = health.lock().unwrap().name;
+    if !update_health(health, stopped, |h| h.failed(error.clone())) {
+        return None;
+    }
+    log::error!("{} failed, restarting in {}s: {}", name, delay.as_secs(), error);
+    Some(delay)
+}
+
+fn restart_delay(failures: u32) -> Duration {
+    let delay = RESTART_DELAY_BASE * 2u32.pow(failures.saturating_sub(1).min(6));
+    delay.min(MAX_RESTART_DELAY)
+}
+
+/// Sleep on a plain thread, waking up regularly to check for a stop.
+/// Returns `false` if stopped.
+fn sleep_unless_stopped(stopped: &CancellationToken, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        if stopped.is_cancelled() {
+            return false;
+        }
+        std::thread::sleep(WATCHER_STOP_POLL.min(deadline - Instant::now()));
+    }
+    !stopped.is_cancelled()
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown error".to_string());
+    format!("panicked: {}", message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh folder for one test, to hold the inbox
+    fn test_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("inbox-services-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn missing_inbox_in_a_local_folder_is_created_on_first_start() {
+        let dir = test_dir();
+        let inbox = dir.join("Inmobiliaria Inbox");
+        let health = Mutex::new(TaskHealth::new("watcher"));
+
+        assert!(wait_for_inbox(&inbox, None, &health, &CancellationToken::new()));
+        assert!(inbox.is_dir());
+        assert_eq!(health.lock().unwrap().state, TaskState::Stopped);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn missing_inbox_under_a_mount_point_is_waited_for_on_first_start() {
+        // `/proc` is always mounted over the root filesystem
+        let inbox = Path::new("/proc/Inmobiliaria Inbox");
+        assert!(!may_create_inbox(inbox, None));
+
+        let health = Mutex::new(TaskHealth::new("watcher"));
+        let stopped = CancellationToken::new();
+        let stop = stopped.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            stop.cancel();
+        });
+        assert!(!wait_for_inbox(inbox, None, &health, &stopped));
+        assert_eq!(health.lock().unwrap().state, TaskState::Waiting);
+        assert!(!inbox.exists());
+    }
+
+    #[test]
+    fn missing_parent_is_never_created() {
+        let dir = test_dir();
+        let inbox = dir.join("Unidad de red").join("Inmobiliaria Inbox");
+        assert!(!may_create_inbox(&inbox, None));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
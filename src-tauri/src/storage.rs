@@ -0,0 +1,229 @@
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+/// Writes take turns: they share the `.tmp` and `.bak` names of their target, and two at once
+/// would delete each other's temp file
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Where `write_atomic` keeps the previous generation of a file, e.g. `config.json.bak`
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bak")
+}
+
+/// Replace the file at `path` so that a crash leaves either the old or the new content,
+/// never a truncated mix. The previous content is kept at `backup_path(path)`.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    write(path, bytes, false)
+}
+
+/// Like `write_atomic`, for files only the current user may read (0600 on unix)
+pub fn write_atomic_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    write(path, bytes, true)
+}
+
+/// Remove a file together with its backup
+pub fn remove_with_backup(path: &Path) -> io::Result<()> {
+    for file in [path.to_path_buf(), backup_path(path)] {
+        match fs::remove_file(&file) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Read and parse `path`, falling back to its backup when the file is damaged.
+/// A missing file is never replaced by the backup: it was removed on purpose.
+/// Returns the value and whether it was recovered from the backup.
+pub fn load_with_backup<T, E: Display>(
+    path: &Path,
+    parse: impl Fn(&[u8]) -> Result<T, E>,
+) -> Option<(T, bool)> {
+    match fs::read(path) {
+        Ok(bytes) => match parse(&bytes) {
+            Ok(value) => return Some((value, false)),
+            Err(e) => log::error!("Failed to parse {:?}: {}", path, e),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => log::error!("Failed to read {:?}: {}", path, e),
+    }
+
+    let backup = backup_path(path);
+    let value = parse(&fs::read(&backup).ok()?).ok()?;
+    log::warn!("Recovered {:?} from {:?}", path, backup);
+    Some((value, true))
+}
+
+fn write(path: &Path, bytes: &[u8], private: bool) -> io::Result<()> {
+    let _turn = WRITE_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // A temp file left by a crash may have other permissions; start from a fresh one
+    let tmp = with_suffix(path, ".tmp");
+    let _ = fs::remove_file(&tmp);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+
+    if path.exists() {
+        let backup = backup_path(path);
+        let _ = fs::remove_file(&backup);
+        if fs::hard_link(path, &backup).is_err() {
+            fs::copy(path, &backup)?;
+        }
+        // The backup may come from a version that didn't restrict permissions
+        #[cfg(unix)]
+        if private {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&backup, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+
+    fs::rename(&tmp, path)?;
+    sync_parent(path);
+    Ok(())
+}
+
+/// Make the rename itself durable
+#[cfg(unix)]
+fn sync_parent(path: &Path) {
+    if let Some(dir) = path.parent().and_then(|p| fs::File::open(p).ok()) {
+        let _ = dir.sync_all();
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) {}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh folder for one test's files
+    fn test_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("inbox-storage-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn parse(bytes: &[u8]) -> Result<String, String> {
+        let text = String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())?;
+        if text.starts_with("ok") {
+            Ok(text)
+        } else {
+            Err(format!("damaged: {}", text))
+        }
+    }
+
+    #[test]
+    fn write_replaces_the_file_and_keeps_the_previous_one() {
+        let dir = test_dir();
+        let path = dir.join("state.json");
+
+        write_atomic(&path, b"ok 1").unwrap();
+        assert!(!backup_path(&path).exists());
+        write_atomic(&path, b"ok 2").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"ok 2");
+        assert_eq!(fs::read(backup_path(&path)).unwrap(), b"ok 1");
+        assert!(!with_suffix(&path, ".tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn overlapping_writes_all_succeed() {
+        let dir = test_dir();
+        let path = dir.join("state.json");
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    for n in 0..20 {
+                        write_atomic(&path, format!("ok {} {}", i, n).as_bytes()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let (content, recovered) = load_with_backup(&path, parse).unwrap();
+        assert!(content.ends_with(" 19"), "{}", content);
+        assert!(!recovered);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_file_is_read_from_the_backup() {
+        let dir = test_dir();
+        let path = dir.join("state.json");
+        write_atomic(&path, b"ok 1").unwrap();
+        write_atomic(&path, b"ok 2").unwrap();
+        fs::write(&path, b"{\"trunc").unwrap();
+
+        assert_eq!(load_with_backup(&path, parse), Some(("ok 1".to_string(), true)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_file_is_not_replaced_by_the_backup() {
+        let dir = test_dir();
+        let path = dir.join("state.json");
+        fs::write(backup_path(&path), b"ok old").unwrap();
+
+        assert_eq!(load_with_backup(&path, parse), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remove_deletes_the_backup_too() {
+        let dir = test_dir();
+        let path = dir.join("state.json");
+        write_atomic(&path, b"ok 1").unwrap();
+        write_atomic(&path, b"ok 2").unwrap();
+
+        remove_with_backup(&path).unwrap();
+        assert!(!path.exists());
+        assert!(!backup_path(&path).exists());
+        // Nothing left to remove is fine
+        remove_with_backup(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_write_is_only_readable_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = test_dir();
+        let path = dir.join("secret");
+        fs::write(&path, b"ok old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_atomic_private(&path, b"ok new").unwrap();
+        for file in [&path, &backup_path(&path)] {
+            let mode = fs::metadata(file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "{:?}", file);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,6 @@
-use crate::uploader::{UploadManager, UploadStatus};
+use crate::auth::{self, SessionState};
+use crate::services::ServiceSupervisor;
+use crate::uploader::{self, UploadManager, UploadProgress, UploadStatus};
 use std::sync::Arc;
 use tauri::{
     image::Image,
@@ -8,7 +10,6 @@ use tauri::{
 };
 
 #[derive(Debug, Clone, PartialEq)]
-#[allow(dead_code)]
 pub enum TrayState {
     Connected,
     Syncing(usize),
@@ -16,6 +17,8 @@ pub enum TrayState {
     Pending(usize),
     Error(usize),
     NotAuthenticated,
+    /// The inbox folder was removed or is on a drive or share that isn't connected
+    InboxUnavailable,
 }
 
 /// Create the initial tray icon with menu
@@ -23,7 +26,7 @@ pub fn create_tray(app: &AppHandle) -> Result<TrayIcon, String> {
     let tray = TrayIconBuilder::with_id("main-tray")
         .tooltip("Inmobiliaria Inbox")
         .icon(load_tray_icon(app, "tray-default"))
-        .menu(&build_menu(app, &TrayState::Connected, &[])?)
+        .menu(&build_menu(app, &TrayState::Connected, &[], &[])?)
         .show_menu_on_left_click(true)
         .build(app)
         .map_err(|e| format!("Failed to create tray: {}", e))?;
@@ -36,9 +39,11 @@ pub fn update_tray(
     app: &AppHandle,
     tray: &TrayIcon,
     upload_manager: &Arc<UploadManager>,
+    services: &ServiceSupervisor,
 ) -> Result<(), String> {
-    let state = determine_state(upload_manager);
+    let state = determine_state(upload_manager, services);
     let recent = upload_manager.get_recent();
+    let progress = upload_manager.get_progress();
 
     // Update icon based on state
     let icon_name = match &state {
@@ -48,6 +53,7 @@ pub fn update_tray(
         TrayState::Pending(_) => "tray-default",
         TrayState::Error(_) => "tray-error",
         TrayState::NotAuthenticated => "tray-offline",
+        TrayState::InboxUnavailable => "tray-error",
     };
 
     let _ = tray.set_icon(Some(load_tray_icon(app, icon_name)));
@@ -55,28 +61,40 @@ pub fn update_tray(
     // Update tooltip
     let tooltip = match &state {
         TrayState::Connected => "Inmobiliaria Inbox — Conectado".to_string(),
-        TrayState::Syncing(n) => format!("Inmobiliaria Inbox — Subiendo {} archivo(s)...", n),
+        TrayState::Syncing(n) => match uploader::overall_percent(&progress) {
+            Some(percent) => format!("Inmobiliaria Inbox — Subiendo {} archivo(s)... {}%", n, percent),
+            None => format!("Inmobiliaria Inbox — Subiendo {} archivo(s)...", n),
+        },
         TrayState::Offline => "Inmobiliaria Inbox — Sin conexión".to_string(),
         TrayState::Pending(n) => format!("Inmobiliaria Inbox — {} pendiente(s)", n),
         TrayState::Error(n) => format!("Inmobiliaria Inbox — {} error(es)", n),
-        TrayState::NotAuthenticated => "Inmobiliaria Inbox — No autenticado".to_string(),
+        TrayState::NotAuthenticated => format!("Inmobiliaria Inbox — {}", session_label()),
+        TrayState::InboxUnavailable => "Inmobiliaria Inbox — Carpeta no disponible".to_string(),
     };
     let _ = tray.set_tooltip(Some(&tooltip));
 
     // Update menu
-    if let Ok(menu) = build_menu(app, &state, &recent) {
+    if let Ok(menu) = build_menu(app, &state, &recent, &progress) {
         let _ = tray.set_menu(Some(menu));
     }
 
     Ok(())
 }
 
-fn determine_state(upload_manager: &Arc<UploadManager>) -> TrayState {
+fn determine_state(upload_manager: &Arc<UploadManager>, services: &ServiceSupervisor) -> TrayState {
+    if !auth::session_state().is_usable() || upload_manager.needs_login() {
+        return TrayState::NotAuthenticated;
+    }
+    // New files can't be noticed until the folder is back
+    if services.inbox_unavailable() {
+        return TrayState::InboxUnavailable;
+    }
     if !upload_manager.is_online() {
         return TrayState::Offline;
     }
-    if upload_manager.is_uploading() {
-        return TrayState::Syncing(upload_manager.queue_size() + 1);
+    let active = upload_manager.active_count();
+    if active > 0 {
+        return TrayState::Syncing(upload_manager.queue_size() + active);
     }
     let recent = upload_manager.get_recent();
     let error_count = recent.iter().filter(|r| r.status == UploadStatus::Failed).count();
@@ -90,10 +108,20 @@ fn determine_state(upload_manager: &Arc<UploadManager>) -> TrayState {
     TrayState::Connected
 }
 
+/// Short description of why the user is not authenticated
+fn session_label() -> &'static str {
+    match auth::session_state() {
+        SessionState::LoggedOut => "No autenticado",
+        SessionState::Revoked => "Sesión revocada",
+        _ => "Sesión vencida",
+    }
+}
+
 fn build_menu(
     app: &AppHandle,
     state: &TrayState,
     recent: &[crate::uploader::RecentUpload],
+    progress: &[UploadProgress],
 ) -> Result<tauri::menu::Menu<tauri::Wry>, String> {
     let open_folder = MenuItemBuilder::with_id("open_folder", "Abrir carpeta")
         .build(app)
@@ -109,7 +137,8 @@ fn build_menu(
         TrayState::Offline => "✕ Sin conexión",
         TrayState::Pending(n) => &format!("● {} pendiente(s) de subida", n),
         TrayState::Error(n) => &format!("⚠ {} archivo(s) con error", n),
-        TrayState::NotAuthenticated => "⚠ No autenticado",
+        TrayState::NotAuthenticated => &format!("⚠ {}", session_label()),
+        TrayState::InboxUnavailable => "⚠ Carpeta Inbox no disponible",
     };
 
     // Status needs to be owned for lifetimes
@@ -119,6 +148,10 @@ fn build_menu(
         .build(app)
         .map_err(|e| e.to_string())?;
 
+    let relogin = MenuItemBuilder::with_id("relogin", "Iniciar sesión de nuevo")
+        .build(app)
+        .map_err(|e| e.to_string())?;
+
     let settings = MenuItemBuilder::with_id("settings", "Configuración...")
         .build(app)
         .map_err(|e| e.to_string())?;
@@ -144,7 +177,18 @@ fn build_menu(
                 UploadStatus::Pending => "●",
                 UploadStatus::Uploading => "↑",
             };
-            let mut label = format!("{} {} ({})", icon, truncate_name(&upload.name, 30), upload.timestamp);
+            let percent = progress
+                .iter()
+                .find(|p| upload.status == UploadStatus::Uploading && p.name == upload.name)
+                .map(|p| format!(" {}%", p.percent))
+                .unwrap_or_default();
+            let mut label = format!(
+                "{} {}{} ({})",
+                icon,
+                truncate_name(&upload.name, 30),
+                percent,
+                upload.timestamp
+            );
             if let Some(ref err) = upload.error {
                 label = format!("{}\n   ↳ {}", label, err);
             }
@@ -158,11 +202,17 @@ fn build_menu(
 
     let recent_submenu = recent_sub.build().map_err(|e| e.to_string())?;
 
-    let menu = MenuBuilder::new(app)
+    let mut menu = MenuBuilder::new(app)
         .item(&open_folder)
         .item(&open_web)
         .separator()
-        .item(&status_item)
+        .item(&status_item);
+
+    if *state == TrayState::NotAuthenticated {
+        menu = menu.item(&relogin);
+    }
+
+    let menu = menu
         .separator()
         .item(&recent_submenu)
         .separator()
@@ -174,16 +224,18 @@ fn build_menu(
     Ok(menu)
 }
 
+/// Shorten `name` to about `max_len` characters, keeping its extension. Counts characters, not
+/// bytes, so names like `Señas/Depósito.pdf` are never cut inside a letter.
 fn truncate_name(name: &str, max_len: usize) -> String {
-    if name.len() <= max_len {
+    if name.chars().count() <= max_len {
         name.to_string()
     } else {
         let ext = std::path::Path::new(name)
             .extension()
             .and_then(|e| e.to_str())
             .unwrap_or("");
-        let stem_max = max_len.saturating_sub(ext.len() + 4); // "..." + "."
-        let stem = &name[..stem_max.min(name.len())];
+        let stem_max = max_len.saturating_sub(ext.chars().count() + 4); // "..." + "."
+        let stem: String = name.chars().take(stem_max).collect();
         if ext.is_empty() {
             format!("{}...", stem)
         } else {
@@ -207,3 +259,23 @@ fn load_tray_icon(_app: &AppHandle, name: &str) -> Image<'static> {
     buf.truncate(info.buffer_size());
     Image::new_owned(buf, info.width, info.height)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_name_is_kept() {
+        assert_eq!(truncate_name("Señas/plano.pdf", 30), "Señas/plano.pdf");
+    }
+
+    #[test]
+    fn long_name_is_cut_between_characters() {
+        let name = "Señas/Depósito garantía ñandú ámbito.pdf";
+        for max_len in 5..name.chars().count() {
+            let short = truncate_name(name, max_len);
+            assert!(short.ends_with("...pdf"), "{}", short);
+        }
+        assert_eq!(truncate_name(name, 14), "Señas/D...pdf");
+    }
+}
@@ -1,11 +1,22 @@
 use crate::auth;
 use crate::config::uploaded_subfolder;
+use crate::error::{AppError, ErrorKind};
+use crate::journal::{JournalData, QueueJournal};
+use crate::validation;
+use crate::watcher;
+use futures_util::{FutureExt, TryStreamExt};
 use reqwest::multipart;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::SystemTime;
+use tokio::sync::{Notify, Semaphore};
 use tokio::time::{sleep, Duration};
+use tokio_util::io::ReaderStream;
+use tokio_util::sync::CancellationToken;
 
 /// Maximum number of recent uploads to track
 const MAX_RECENT: usize = 15;
@@ -16,9 +27,18 @@ const RETRY_DELAY_BASE_SECS: u64 = 5;
 /// Max retries before giving up on a single file
 const MAX_RETRIES: u32 = 10;
 
+/// How long the worker idles when nothing is ready, unless woken by a new file
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long the journal writer waits after a change, so a burst of changes is saved at once
+const JOURNAL_WRITE_DELAY: Duration = Duration::from_millis(500);
+
 /// Maximum file size: 200 MB
 const MAX_FILE_SIZE: u64 = 200 * 1024 * 1024;
 
+/// Size of the chunks read from disk while streaming a file body
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RecentUpload {
     pub name: String,
@@ -26,6 +46,8 @@ pub struct RecentUpload {
     pub timestamp: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<ErrorKind>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -36,85 +58,400 @@ pub enum UploadStatus {
     Uploading,
 }
 
-#[derive(Debug, Clone)]
-struct QueueItem {
+/// Live progress of a file that is currently being uploaded
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadProgress {
+    pub name: String,
+    pub bytes_sent: u64,
+    pub total_bytes: u64,
+    /// Average throughput since the upload started
+    pub bytes_per_sec: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta_secs: Option<u64>,
+    pub percent: u8,
+    #[serde(skip)]
+    started: std::time::Instant,
+}
+
+impl UploadProgress {
+    fn new(name: String, total_bytes: u64) -> Self {
+        Self {
+            name,
+            bytes_sent: 0,
+            total_bytes,
+            bytes_per_sec: 0.0,
+            eta_secs: None,
+            percent: 0,
+            started: std::time::Instant::now(),
+        }
+    }
+
+    fn update(&mut self, bytes_sent: u64) {
+        self.bytes_sent = bytes_sent;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.bytes_per_sec = bytes_sent as f64 / elapsed;
+        }
+        let remaining = self.total_bytes.saturating_sub(bytes_sent);
+        self.eta_secs = if self.bytes_per_sec > 0.0 {
+            Some((remaining as f64 / self.bytes_per_sec).ceil() as u64)
+        } else {
+            None
+        };
+        self.percent = (bytes_sent.min(self.total_bytes) * 100)
+            .checked_div(self.total_bytes)
+            .unwrap_or(0) as u8;
+    }
+}
+
+/// Combined percentage of all uploads in progress, weighted by size
+pub fn overall_percent(progress: &[UploadProgress]) -> Option<u8> {
+    let total: u64 = progress.iter().map(|p| p.total_bytes).sum();
+    if total == 0 {
+        return None;
+    }
+    let sent: u64 = progress.iter().map(|p| p.bytes_sent.min(p.total_bytes)).sum();
+    Some(((sent * 100) / total) as u8)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueItem {
     path: PathBuf,
+    /// Shown in the history and used to find the item's entry there
+    #[serde(default)]
+    name: String,
     retries: u32,
+    /// Earliest time the next attempt may start (set after a failed attempt)
+    #[serde(default, alias = "next_attempt_at")]
+    not_before: Option<chrono::DateTime<chrono::Utc>>,
+    /// Reason of the last failed attempt
+    #[serde(default)]
+    last_error: Option<String>,
+    /// Bytes sent so far while the item is being uploaded
+    #[serde(skip)]
+    progress: Option<UploadProgress>,
+}
+
+/// A handled file that stays in the inbox, as written to the journal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeftBehind {
+    path: PathBuf,
+    /// Modification time when it was handled; the file is picked up again once it changes
+    modified: Option<SystemTime>,
 }
 
 /// Shared upload state
 pub struct UploadManager {
     queue: Arc<Mutex<VecDeque<QueueItem>>>,
+    /// Items taken from the queue that are currently being processed
+    in_flight: Arc<Mutex<Vec<QueueItem>>>,
+    /// Files that stay in the inbox after being handled (failed for good, or uploaded but not
+    /// removed), with their modification time then; they're picked up again once changed
+    left_behind: Mutex<HashMap<PathBuf, Option<SystemTime>>>,
     recent: Arc<Mutex<VecDeque<RecentUpload>>>,
-    is_uploading: Arc<Mutex<bool>>,
     is_online: Arc<Mutex<bool>>,
+    /// Set from the `Retry-After` of a 429/503 response; no upload starts before then, since
+    /// the whole server is rate limited or down
+    server_not_before: Mutex<Option<chrono::DateTime<chrono::Utc>>>,
+    /// Set by a failed upload so the worker re-checks the server right away
+    force_health_check: AtomicBool,
+    /// Wakes the worker when a new file is queued
+    queue_changed: Notify,
+    /// Set when the token can't be refreshed; the queue is paused until the user logs in
+    needs_login: AtomicBool,
+    /// Number of times history was imported from another computer
+    history_imports: AtomicUsize,
+    /// Serializes token refreshes triggered by concurrent uploads
+    refresh_lock: tokio::sync::Mutex<()>,
+    journal: Mutex<QueueJournal>,
+    /// Set when the journal is out of date, until the journal writer saves it
+    journal_dirty: Mutex<bool>,
+    journal_changed: Condvar,
 }
 
 impl UploadManager {
-    pub fn new() -> Self {
+    /// Create the manager and replay whatever the journal held at the last shutdown
+    pub fn new(journal: QueueJournal) -> Self {
+        let data = journal.load();
+
+        // Drop entries whose file disappeared while the app was closed
+        let queue: VecDeque<QueueItem> = data
+            .queue
+            .into_iter()
+            .filter(|item| item.path.exists())
+            .collect();
+
+        let queued_names: Vec<String> = queue.iter().map(|item| item.name()).collect();
+        let recent: VecDeque<RecentUpload> = data
+            .recent
+            .into_iter()
+            .filter_map(|mut entry| match entry.status {
+                UploadStatus::Pending | UploadStatus::Uploading => {
+                    // Only keep in-progress entries that are still queued
+                    if queued_names.contains(&entry.name) {
+                        entry.status = UploadStatus::Pending;
+                        Some(entry)
+                    } else {
+                        None
+                    }
+                }
+                _ => Some(entry),
+            })
+            .take(MAX_RECENT)
+            .collect();
+
+        if !queue.is_empty() {
+            log::info!("Restored {} queued file(s) from journal", queue.len());
+        }
+
+        // Files that were removed or moved meanwhile need no entry
+        let left_behind: HashMap<PathBuf, Option<SystemTime>> = data
+            .left_behind
+            .into_iter()
+            .filter(|entry| entry.path.exists())
+            .map(|entry| (entry.path, entry.modified))
+            .collect();
+
         Self {
-            queue: Arc::new(Mutex::new(VecDeque::new())),
-            recent: Arc::new(Mutex::new(VecDeque::new())),
-            is_uploading: Arc::new(Mutex::new(false)),
+            queue: Arc::new(Mutex::new(queue)),
+            in_flight: Arc::new(Mutex::new(Vec::new())),
+            left_behind: Mutex::new(left_behind),
+            recent: Arc::new(Mutex::new(recent)),
             is_online: Arc::new(Mutex::new(true)),
+            server_not_before: Mutex::new(None),
+            force_health_check: AtomicBool::new(false),
+            queue_changed: Notify::new(),
+            needs_login: AtomicBool::new(false),
+            history_imports: AtomicUsize::new(0),
+            refresh_lock: tokio::sync::Mutex::new(()),
+            journal: Mutex::new(journal),
+            journal_dirty: Mutex::new(false),
+            journal_changed: Condvar::new(),
         }
     }
 
-    /// Add a file to the upload queue
-    pub fn enqueue(&self, path: PathBuf) {
-        let mut queue = self.queue.lock().unwrap();
+    /// Save the journal on a thread of its own, `JOURNAL_WRITE_DELAY` after a change. A folder
+    /// of new files then costs one write, and no upload task waits for the disk.
+    pub fn start_journal_writer(self: &Arc<Self>) {
+        let manager = Arc::clone(self);
+        std::thread::spawn(move || loop {
+            {
+                let mut dirty = lock(&manager.journal_dirty);
+                while !*dirty {
+                    dirty = manager
+                        .journal_changed
+                        .wait(dirty)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+            }
+            std::thread::sleep(JOURNAL_WRITE_DELAY);
+            manager.flush_journal();
+        });
+    }
 
-        // Avoid duplicates
-        if queue.iter().any(|item| item.path == path) {
-            return;
+    /// Mark the journal out of date; the journal writer saves it shortly
+    fn persist(&self) {
+        *lock(&self.journal_dirty) = true;
+        self.journal_changed.notify_one();
+    }
+
+    /// Write the current queue, in-flight items, recent history and left-behind files to the
+    /// journal now
+    pub fn flush_journal(&self) {
+        // Changes made from here on are saved by the next write
+        *lock(&self.journal_dirty) = false;
+        // Held while taking the snapshot, so a slower concurrent save can't write older state
+        let journal = lock(&self.journal);
+        let mut queue: Vec<QueueItem> = lock(&self.in_flight).clone();
+        queue.extend(lock(&self.queue).iter().cloned());
+        let recent: Vec<RecentUpload> = lock(&self.recent).iter().cloned().collect();
+        let left_behind: Vec<LeftBehind> = lock(&self.left_behind)
+            .iter()
+            .map(|(path, modified)| LeftBehind {
+                path: path.clone(),
+                modified: *modified,
+            })
+            .collect();
+
+        let data = JournalData {
+            queue,
+            recent,
+            left_behind,
+        };
+        if let Err(e) = journal.save(&data) {
+            log::error!("{}", e);
         }
+    }
 
-        log::info!("Enqueuing file: {:?}", path);
+    /// Add a file in the inbox to the upload queue
+    pub fn enqueue(&self, inbox_path: &Path, path: PathBuf) {
+        {
+            // Held until the item is pushed, so two watchers can't both add the same path
+            let mut queue = lock(&self.queue);
 
-        // Add to recent as pending
-        let file_name = path
-            .file_name()
-            .unwrap_or_default()
-            .to_string_lossy()
-            .to_string();
+            // Avoid duplicates (including files restored from the journal or being uploaded)
+            if queue.iter().any(|item| item.path == path)
+                || lock(&self.in_flight).iter().any(|item| item.path == path)
+            {
+                return;
+            }
 
-        self.add_recent(RecentUpload {
-            name: file_name,
-            status: UploadStatus::Pending,
-            timestamp: chrono::Local::now().format("%H:%M:%S").to_string(),
-            error: None,
-        });
+            log::info!("Enqueuing file: {:?}", path);
+            lock(&self.left_behind).remove(&path);
 
-        queue.push_back(QueueItem { path, retries: 0 });
+            // Add to recent as pending; files in subfolders are told apart by their folder
+            let name = watcher::inbox_relative(inbox_path, &path);
+
+            self.add_recent(RecentUpload {
+                name: name.clone(),
+                status: UploadStatus::Pending,
+                timestamp: chrono::Local::now().format("%H:%M:%S").to_string(),
+                error: None,
+                error_kind: None,
+            });
+
+            queue.push_back(QueueItem {
+                path,
+                name,
+                retries: 0,
+                not_before: None,
+                last_error: None,
+                progress: None,
+            });
+        }
+        self.persist();
+        self.queue_changed.notify_one();
+    }
+
+    /// Whether `path` is queued, being uploaded, or was already handled and hasn't changed since
+    pub fn is_tracked(&self, path: &Path) -> bool {
+        if lock(&self.queue).iter().any(|item| item.path == path)
+            || lock(&self.in_flight).iter().any(|item| item.path == path)
+        {
+            return true;
+        }
+        match lock(&self.left_behind).get(path) {
+            Some(modified) => *modified == modified_time(path),
+            None => false,
+        }
+    }
+
+    /// Remember a handled file that stays in the inbox, so it isn't picked up again until it
+    /// changes
+    fn leave_behind(&self, path: &Path) {
+        self.remember_left_behind(path);
+        // Kept across restarts, so the file isn't uploaded or reported again on the next launch
+        self.persist();
+    }
+
+    /// `leave_behind` without saving the journal, for callers that save it right after
+    fn remember_left_behind(&self, path: &Path) {
+        lock(&self.left_behind).insert(path.to_path_buf(), modified_time(path));
     }
 
     /// Get the current queue size
     pub fn queue_size(&self) -> usize {
-        self.queue.lock().unwrap().len()
+        lock(&self.queue).len()
+    }
+
+    /// Number of files currently being uploaded
+    pub fn active_count(&self) -> usize {
+        lock(&self.in_flight).len()
     }
 
     /// Check if currently uploading
     pub fn is_uploading(&self) -> bool {
-        *self.is_uploading.lock().unwrap()
+        self.active_count() > 0
+    }
+
+    /// Get the progress of every file currently being uploaded
+    pub fn get_progress(&self) -> Vec<UploadProgress> {
+        lock(&self.in_flight)
+            .iter()
+            .filter_map(|item| item.progress.clone())
+            .collect()
+    }
+
+    fn set_progress(&self, path: &Path, bytes_sent: u64, total_bytes: u64) {
+        let mut in_flight = lock(&self.in_flight);
+        if let Some(item) = in_flight.iter_mut().find(|item| item.path == path) {
+            // Each attempt reports 0 bytes first; a retry after a token refresh starts over
+            if bytes_sent == 0 || item.progress.is_none() {
+                item.progress = Some(UploadProgress::new(item.name(), total_bytes));
+            }
+            if let Some(progress) = &mut item.progress {
+                progress.update(bytes_sent);
+            }
+        }
     }
 
     /// Get recent uploads
     pub fn get_recent(&self) -> Vec<RecentUpload> {
-        self.recent.lock().unwrap().iter().cloned().collect()
+        lock(&self.recent).iter().cloned().collect()
     }
 
     /// Check online status
     pub fn is_online(&self) -> bool {
-        *self.is_online.lock().unwrap()
+        *lock(&self.is_online)
     }
 
     /// Set online status
     pub fn set_online(&self, online: bool) {
-        *self.is_online.lock().unwrap() = online;
+        *lock(&self.is_online) = online;
+    }
+
+    /// Whether uploads are paused because the session expired
+    pub fn needs_login(&self) -> bool {
+        self.needs_login.load(Ordering::SeqCst)
+    }
+
+    fn set_needs_login(&self, needs_login: bool) {
+        self.needs_login.store(needs_login, Ordering::SeqCst);
+    }
+
+    /// Resume a queue paused by an expired session, once the user logged in again
+    pub fn resume_after_login(&self) {
+        if self.needs_login.swap(false, Ordering::SeqCst) {
+            log::info!("Logged in again, resuming uploads");
+            self.queue_changed.notify_one();
+        }
+    }
+
+    /// Append upload history brought over from another computer after the local entries.
+    /// Returns how many entries were added.
+    pub fn import_recent(&self, entries: Vec<RecentUpload>) -> usize {
+        let added = {
+            let mut recent = lock(&self.recent);
+            let mut added = 0;
+            for entry in entries {
+                if recent.len() >= MAX_RECENT {
+                    break;
+                }
+                if recent
+                    .iter()
+                    .any(|r| r.name == entry.name && r.timestamp == entry.timestamp)
+                {
+                    continue;
+                }
+                recent.push_back(entry);
+                added += 1;
+            }
+            added
+        };
+        if added > 0 {
+            self.history_imports.fetch_add(1, Ordering::SeqCst);
+        }
+        self.persist();
+        added
+    }
+
+    /// Changes whenever history is imported, so its entries aren't notified as new uploads
+    pub fn history_imports(&self) -> usize {
+        self.history_imports.load(Ordering::SeqCst)
     }
 
     fn add_recent(&self, entry: RecentUpload) {
-        let mut recent = self.recent.lock().unwrap();
+        let mut recent = lock(&self.recent);
         recent.push_front(entry);
         while recent.len() > MAX_RECENT {
             recent.pop_back();
@@ -122,34 +459,63 @@ impl UploadManager {
     }
 
     fn update_recent_status(&self, name: &str, status: UploadStatus) {
-        self.update_recent_status_with_error(name, status, None);
+        self.update_recent_status_with_error(name, status, None, None);
     }
 
-    fn update_recent_status_with_error(&self, name: &str, status: UploadStatus, error: Option<String>) {
-        let mut recent = self.recent.lock().unwrap();
-        if let Some(entry) = recent.iter_mut().find(|r| r.name == name) {
-            entry.status = status;
-            entry.error = error;
+    fn update_recent_status_with_error(
+        &self,
+        name: &str,
+        status: UploadStatus,
+        error: Option<String>,
+        error_kind: Option<ErrorKind>,
+    ) {
+        {
+            let mut recent = lock(&self.recent);
+            if let Some(entry) = recent.iter_mut().find(|r| r.name == name) {
+                entry.status = status;
+                entry.error = error;
+                entry.error_kind = error_kind;
+            }
         }
+        self.persist();
     }
 
-    /// Start the upload worker loop — runs indefinitely
+    /// Remove an item from the in-flight list once it is done (uploaded, given up or re-queued)
+    fn finish_in_flight(&self, path: &Path) {
+        lock(&self.in_flight).retain(|item| item.path != path);
+    }
+
+    /// Start the upload worker loop — runs until `stopped` is cancelled, keeping up to
+    /// `max_concurrent` uploads in flight at once. Cancelling also interrupts those uploads.
     pub async fn start_worker(
         self: Arc<Self>,
         server_url: String,
         delete_after_upload: bool,
         inbox_path: String,
+        max_concurrent: usize,
+        stopped: CancellationToken,
     ) {
-        log::info!("Upload worker started");
+        let max_concurrent = max_concurrent.clamp(1, validation::MAX_CONCURRENT_UPLOADS);
+        log::info!("Upload worker started ({} concurrent upload(s))", max_concurrent);
+
+        let slots = Arc::new(Semaphore::new(max_concurrent));
 
         // Only check server health periodically, not every loop iteration
         let mut last_health_check = std::time::Instant::now() - std::time::Duration::from_secs(60);
         const HEALTH_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
 
         loop {
-            // Check connectivity only every HEALTH_CHECK_INTERVAL
-            if last_health_check.elapsed() >= HEALTH_CHECK_INTERVAL {
-                let online = check_server(&server_url).await;
+            // Check connectivity only every HEALTH_CHECK_INTERVAL, or right after a failure
+            if last_health_check.elapsed() >= HEALTH_CHECK_INTERVAL
+                || self.force_health_check.swap(false, Ordering::SeqCst)
+            {
+                let online = match check_server(&server_url).await {
+                    Ok(()) => true,
+                    Err(e) => {
+                        log::debug!("Health check failed: {}", e);
+                        false
+                    }
+                };
                 self.set_online(online);
                 last_health_check = std::time::Instant::now();
 
@@ -160,136 +526,435 @@ impl UploadManager {
                 }
             }
 
-            // Try to get next item from queue
-            let item = {
-                let mut queue = self.queue.lock().unwrap();
-                queue.pop_front()
+            // Paused until the user logs in again
+            if self.needs_login() {
+                tokio::select! {
+                    _ = self.queue_changed.notified() => {}
+                    _ = sleep(IDLE_POLL_INTERVAL) => {}
+                }
+                continue;
+            }
+
+            // Wait for a free upload slot
+            let permit = match Arc::clone(&slots).acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => return,
             };
 
-            match item {
-                Some(mut item) => {
-                    let file_name = item
-                        .path
-                        .file_name()
-                        .unwrap_or_default()
-                        .to_string_lossy()
-                        .to_string();
-
-                    *self.is_uploading.lock().unwrap() = true;
-                    self.update_recent_status(&file_name, UploadStatus::Uploading);
-
-                    // Validate file before attempting upload
-                    let validation_err = match std::fs::metadata(&item.path) {
-                        Ok(meta) => {
-                            let size = meta.len();
-                            if size == 0 {
-                                Some("Archivo vacío".to_string())
-                            } else if size > MAX_FILE_SIZE {
-                                Some(format!(
-                                    "Archivo demasiado grande ({:.0} MB, máx {:.0} MB)",
-                                    size as f64 / 1_048_576.0,
-                                    MAX_FILE_SIZE as f64 / 1_048_576.0
-                                ))
-                            } else {
-                                None
+            match self.take_next_ready() {
+                Some(item) => {
+                    let manager = Arc::clone(&self);
+                    let server_url = server_url.clone();
+                    let inbox_path = inbox_path.clone();
+                    let stopped = stopped.clone();
+                    tokio::spawn(async move {
+                        let upload = Arc::clone(&manager).process_item(
+                            item.clone(),
+                            &server_url,
+                            delete_after_upload,
+                            &inbox_path,
+                        );
+                        // Dropping the upload aborts the request; the file is left as it was
+                        tokio::select! {
+                            result = AssertUnwindSafe(upload).catch_unwind() => {
+                                if result.is_err() {
+                                    manager.recover_panicked(item);
+                                }
                             }
+                            _ = stopped.cancelled() => manager.requeue_interrupted(item),
                         }
-                        Err(e) => Some(format!("No se puede leer: {}", e)),
-                    };
-
-                    if let Some(reason) = validation_err {
-                        log::error!("Skipping {}: {}", file_name, reason);
-                        self.update_recent_status_with_error(
-                            &file_name,
-                            UploadStatus::Failed,
-                            Some(reason),
-                        );
-                        *self.is_uploading.lock().unwrap() = false;
-                        continue;
+                        drop(permit);
+                    });
+                }
+                None => {
+                    // Nothing ready to upload: sleep until the next retry is due,
+                    // a new file arrives, or the idle interval elapses
+                    drop(permit);
+                    let wait = self
+                        .time_until_next_ready()
+                        .map_or(IDLE_POLL_INTERVAL, |d| d.min(IDLE_POLL_INTERVAL));
+                    tokio::select! {
+                        _ = self.queue_changed.notified() => {}
+                        _ = sleep(wait) => {}
                     }
+                }
+            }
+        }
+    }
+
+    /// Take the first queued item whose `not_before` time has passed and mark it in flight.
+    /// Items still waiting for a retry are skipped, so they never block newer files.
+    fn take_next_ready(&self) -> Option<QueueItem> {
+        let now = chrono::Utc::now();
+        if lock(&self.server_not_before).is_some_and(|at| at > now) {
+            return None;
+        }
+        let mut queue = lock(&self.queue);
+        let index = queue
+            .iter()
+            .position(|item| item.not_before.is_none_or(|at| at <= now))?;
+        let item = queue.remove(index)?;
+        lock(&self.in_flight).push(item.clone());
+        Some(item)
+    }
 
-                    match upload_file(&item.path, &server_url).await {
-                        Ok(_) => {
-                            log::info!("Successfully uploaded: {}", file_name);
-                            self.update_recent_status(&file_name, UploadStatus::Success);
+    /// Queue an item again after its upload task panicked, so it isn't stuck in flight forever
+    fn recover_panicked(&self, mut item: QueueItem) {
+        // A panic after the item was handled leaves nothing to undo
+        if !lock(&self.in_flight).iter().any(|i| i.path == item.path) {
+            return;
+        }
+        let name = item.name();
+        log::error!("Upload task for {} panicked", name);
+        self.finish_in_flight(&item.path);
 
-                            // Handle post-upload file cleanup
-                            if delete_after_upload {
-                                if let Err(e) = std::fs::remove_file(&item.path) {
-                                    log::error!("Failed to delete file after upload: {}", e);
-                                }
-                            } else {
-                                // Move to "Subidos" subfolder
-                                let dest_dir = uploaded_subfolder(&inbox_path);
-                                if let Err(e) = std::fs::create_dir_all(&dest_dir) {
-                                    log::error!("Failed to create Subidos folder: {}", e);
-                                } else {
-                                    let dest = dest_dir.join(&file_name);
-                                    if let Err(e) = std::fs::rename(&item.path, &dest) {
-                                        log::error!("Failed to move file to Subidos: {}", e);
-                                    }
-                                }
-                            }
+        item.retries += 1;
+        if item.retries < MAX_RETRIES {
+            let delay = backoff_delay_secs(item.retries);
+            item.not_before = Some(chrono::Utc::now() + chrono::Duration::seconds(delay as i64));
+            item.progress = None;
+            lock(&self.queue).push_back(item);
+            self.update_recent_status(&name, UploadStatus::Pending);
+        } else {
+            self.leave_behind(&item.path);
+            self.update_recent_status_with_error(
+                &name,
+                UploadStatus::Failed,
+                Some("Error inesperado al subir el archivo".to_string()),
+                None,
+            );
+        }
+    }
 
-                            *self.is_uploading.lock().unwrap() = false;
-                        }
-                        Err(e) => {
-                            log::error!("Upload failed for {}: {}", file_name, e);
-
-                            let user_error = humanize_error(&e);
-
-                            // Force a health check on next iteration
-                            last_health_check = std::time::Instant::now() - HEALTH_CHECK_INTERVAL;
-
-                            item.retries += 1;
-                            *self.is_uploading.lock().unwrap() = false;
-
-                            if item.retries < MAX_RETRIES {
-                                // Re-enqueue with exponential backoff
-                                self.update_recent_status_with_error(
-                                    &file_name,
-                                    UploadStatus::Pending,
-                                    Some(format!("Reintentando ({}/{}): {}", item.retries, MAX_RETRIES, user_error)),
-                                );
-                                self.queue.lock().unwrap().push_back(item.clone());
-                                let delay =
-                                    RETRY_DELAY_BASE_SECS * 2u64.pow(item.retries.min(6));
-                                log::info!(
-                                    "Retrying {} in {}s (attempt {}/{})",
-                                    file_name,
-                                    delay,
-                                    item.retries,
-                                    MAX_RETRIES
-                                );
-                                sleep(Duration::from_secs(delay)).await;
-                            } else {
-                                log::error!(
-                                    "Giving up on {} after {} retries",
-                                    file_name,
-                                    MAX_RETRIES
-                                );
-                                self.update_recent_status_with_error(
-                                    &file_name,
-                                    UploadStatus::Failed,
-                                    Some(user_error),
-                                );
-                            }
+    /// Queue an item again, first in line, after its upload was interrupted by a stop or a
+    /// restart. It didn't fail, so it doesn't count as a retry.
+    fn requeue_interrupted(&self, mut item: QueueItem) {
+        if !lock(&self.in_flight).iter().any(|i| i.path == item.path) {
+            return;
+        }
+        let name = item.name();
+        log::info!("Upload of {} interrupted, keeping it queued", name);
+        self.finish_in_flight(&item.path);
+        item.progress = None;
+        lock(&self.queue).push_front(item);
+        self.update_recent_status(&name, UploadStatus::Pending);
+    }
+
+    /// Time left until the earliest waiting item becomes eligible, if any are queued
+    fn time_until_next_ready(&self) -> Option<Duration> {
+        let now = chrono::Utc::now();
+        let wait_until = |at: Option<chrono::DateTime<chrono::Utc>>| {
+            at.and_then(|at| (at - now).to_std().ok())
+                .unwrap_or(Duration::ZERO)
+        };
+        let server_wait = wait_until(*lock(&self.server_not_before));
+        lock(&self.queue)
+            .iter()
+            .map(|item| wait_until(item.not_before).max(server_wait))
+            .min()
+    }
+
+    /// Hold off every upload for `wait`, as asked by a rate-limited or unavailable server
+    fn pause_server(&self, wait: Duration) {
+        let until = chrono::Utc::now() + chrono::Duration::seconds(wait.as_secs() as i64);
+        let mut not_before = lock(&self.server_not_before);
+        if not_before.is_none_or(|at| at < until) {
+            log::warn!("Server asked to wait {}s, pausing all uploads", wait.as_secs());
+            *not_before = Some(until);
+        }
+    }
+
+    /// The session is gone: keep the file queued untouched and pause every upload until the
+    /// user logs in again
+    fn pause_for_login(&self, item: QueueItem) {
+        let name = item.name();
+        log::warn!("Session expired, pausing uploads until login ({})", name);
+        self.set_needs_login(true);
+        self.finish_in_flight(&item.path);
+        lock(&self.queue).push_front(item);
+        self.update_recent_status_with_error(
+            &name,
+            UploadStatus::Pending,
+            Some("Esperando inicio de sesión".to_string()),
+            Some(ErrorKind::Auth),
+        );
+    }
+
+    /// Upload one item and handle its outcome. Failed items go back to the queue
+    /// with their own retry time instead of holding up the worker.
+    async fn process_item(
+        self: Arc<Self>,
+        mut item: QueueItem,
+        server_url: &str,
+        delete_after_upload: bool,
+        inbox_path: &str,
+    ) {
+        let name = item.name();
+        self.update_recent_status(&name, UploadStatus::Uploading);
+
+        // Validate file before attempting upload
+        let validation_err = match std::fs::metadata(&item.path) {
+            Ok(meta) => {
+                let size = meta.len();
+                if size == 0 {
+                    Some(AppError::EmptyFile)
+                } else if size > MAX_FILE_SIZE {
+                    Some(AppError::FileTooLarge {
+                        size,
+                        max: MAX_FILE_SIZE,
+                    })
+                } else {
+                    None
+                }
+            }
+            Err(e) => Some(AppError::Io(e.to_string())),
+        };
+
+        if let Some(err) = validation_err {
+            log::error!("Skipping {}: {}", name, err);
+            self.finish_in_flight(&item.path);
+            self.leave_behind(&item.path);
+            self.update_recent_status_with_error(
+                &name,
+                UploadStatus::Failed,
+                Some(err.user_message()),
+                Some(err.kind()),
+            );
+            return;
+        }
+
+        // Files in a subfolder are tagged with it, e.g. `Facturas` for `Inbox/Facturas/x.pdf`
+        let folder = item.folder(inbox_path);
+        let result = match self
+            .upload_with_refresh(&item.path, folder.as_deref(), server_url)
+            .await
+        {
+            Err(AppError::NotAuthenticated) => {
+                self.pause_for_login(item);
+                return;
+            }
+            result => result,
+        };
+
+        match result {
+            Ok(_) => {
+                log::info!("Successfully uploaded: {}", name);
+
+                // Clean up before the journal records the upload: a crash in between then
+                // finds the item queued for a file that's gone, instead of a file in the inbox
+                // that nothing remembers, which would be uploaded again
+                if delete_after_upload {
+                    if let Err(e) = std::fs::remove_file(&item.path) {
+                        log::error!("Failed to delete file after upload: {}", e);
+                        self.remember_left_behind(&item.path);
+                    }
+                } else {
+                    // Move to "Subidos" subfolder, in the same subfolders it was in
+                    let mut dest_dir = uploaded_subfolder(inbox_path);
+                    if let Some(folder) = &folder {
+                        dest_dir.extend(folder.split('/'));
+                    }
+                    if let Err(e) = std::fs::create_dir_all(&dest_dir) {
+                        log::error!("Failed to create Subidos folder: {}", e);
+                        self.remember_left_behind(&item.path);
+                    } else {
+                        let dest = dest_dir.join(item.path.file_name().unwrap_or_default());
+                        if let Err(e) = std::fs::rename(&item.path, &dest) {
+                            log::error!("Failed to move file to Subidos: {}", e);
+                            self.remember_left_behind(&item.path);
                         }
                     }
                 }
-                None => {
-                    // Queue is empty, wait before checking again
-                    sleep(Duration::from_secs(2)).await;
+
+                self.finish_in_flight(&item.path);
+                self.update_recent_status(&name, UploadStatus::Success);
+            }
+            Err(e) => {
+                log::error!("Upload failed for {}: {}", name, e);
+
+                let user_error = e.user_message();
+
+                // Force a health check on the worker's next iteration
+                if e.is_connectivity() {
+                    self.force_health_check.store(true, Ordering::SeqCst);
+                }
+
+                item.retries += 1;
+                item.last_error = Some(user_error.clone());
+                self.finish_in_flight(&item.path);
+
+                // Other files would hit the same rate-limited or down server
+                if let Some(wait) = e.retry_after() {
+                    self.pause_server(wait);
+                }
+
+                if e.is_retryable() && item.retries < MAX_RETRIES {
+                    // Re-enqueue with the server's Retry-After, or jittered exponential backoff
+                    let delay = e
+                        .retry_after()
+                        .map(|d| d.as_secs())
+                        .unwrap_or_else(|| backoff_delay_secs(item.retries));
+                    item.not_before =
+                        Some(chrono::Utc::now() + chrono::Duration::seconds(delay as i64));
+                    log::info!(
+                        "Retrying {} in {}s (attempt {}/{})",
+                        name,
+                        delay,
+                        item.retries,
+                        MAX_RETRIES
+                    );
+                    lock(&self.queue).push_back(item.clone());
+                    self.update_recent_status_with_error(
+                        &name,
+                        UploadStatus::Pending,
+                        Some(format!(
+                            "Reintentando ({}/{}): {}",
+                            item.retries, MAX_RETRIES, user_error
+                        )),
+                        Some(e.kind()),
+                    );
+                } else {
+                    if e.is_retryable() {
+                        log::error!("Giving up on {} after {} retries", name, MAX_RETRIES);
+                    } else {
+                        log::error!("Not retrying {}: error is permanent", name);
+                    }
+                    self.leave_behind(&item.path);
+                    self.update_recent_status_with_error(
+                        &name,
+                        UploadStatus::Failed,
+                        Some(user_error),
+                        Some(e.kind()),
+                    );
                 }
             }
         }
     }
 }
 
-/// Upload a single file to PocketBase
-async fn upload_file(path: &PathBuf, server_url: &str) -> Result<(), String> {
-    let token = auth::get_token().ok_or("Not authenticated")?;
-    let user_id = auth::get_user_id().ok_or("No user ID found")?;
+impl UploadManager {
+    /// Upload a file; on 401/403 refresh the token once and retry the request.
+    /// Returns `NotAuthenticated` when the session can't be refreshed anymore, or the server
+    /// still rejects it after the refresh.
+    async fn upload_with_refresh(
+        self: &Arc<Self>,
+        path: &Path,
+        folder: Option<&str>,
+        server_url: &str,
+    ) -> Result<(), AppError> {
+        let used_token = auth::get_token();
+        match self.upload_with_progress(path, folder, server_url).await {
+            Err(AppError::AuthExpired | AppError::Forbidden) => {
+                self.refresh_session(server_url, used_token).await?;
+                let result = self.upload_with_progress(path, folder, server_url).await;
+                after_refresh(result)
+            }
+            result => result,
+        }
+    }
+
+    async fn upload_with_progress(
+        self: &Arc<Self>,
+        path: &Path,
+        folder: Option<&str>,
+        server_url: &str,
+    ) -> Result<(), AppError> {
+        let manager = Arc::clone(self);
+        let progress_path = path.to_path_buf();
+        let on_progress = move |sent, total| manager.set_progress(&progress_path, sent, total);
+        upload_file(path, folder, server_url, on_progress).await
+    }
+
+    /// Refresh the stored token after it was rejected. Concurrent uploads share one refresh:
+    /// if another task already replaced `used_token`, the new token is used as is.
+    async fn refresh_session(
+        &self,
+        server_url: &str,
+        used_token: Option<String>,
+    ) -> Result<(), AppError> {
+        let _guard = self.refresh_lock.lock().await;
+        if auth::get_token() != used_token {
+            return Ok(());
+        }
+        match auth::refresh_token(server_url).await {
+            Ok(_) => {
+                log::info!("Auth token refreshed after rejection");
+                Ok(())
+            }
+            Err(e) if e.kind() == ErrorKind::Auth || e.kind() == ErrorKind::Forbidden => {
+                log::warn!("Token refresh rejected: {}", e);
+                Err(AppError::NotAuthenticated)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl QueueItem {
+    /// Path inside the inbox, like `Facturas/x.pdf`; just the file name for items journaled by
+    /// older versions
+    fn name(&self) -> String {
+        if !self.name.is_empty() {
+            return self.name.clone();
+        }
+        self.path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// The inbox subfolder the file is in, like `Facturas/2024`; `None` for files at the top
+    fn folder(&self, inbox_path: &str) -> Option<String> {
+        let parent = self.path.parent()?;
+        let folder = watcher::inbox_relative(Path::new(inbox_path), parent);
+        (!folder.is_empty() && parent.starts_with(inbox_path)).then_some(folder)
+    }
+}
+
+/// Outcome of the attempt made with a refreshed token. A 401 then means the session is no
+/// good anymore, so the user has to log in again; retrying would only fail the same way.
+fn after_refresh(result: Result<(), AppError>) -> Result<(), AppError> {
+    match result {
+        Err(AppError::AuthExpired) => {
+            log::warn!("Upload still rejected after refreshing the token");
+            Err(AppError::NotAuthenticated)
+        }
+        result => result,
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Lock shared state even if a task panicked while holding it, so a restarted
+/// worker doesn't fail again on the same poisoned mutex
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Exponential backoff with "equal jitter": half the delay is fixed, the other half random,
+/// so files that failed together don't all retry at the same instant
+fn backoff_delay_secs(retries: u32) -> u64 {
+    let delay = RETRY_DELAY_BASE_SECS * 2u64.pow(retries.min(6));
+    let half = delay / 2;
+    half + fastrand::u64(0..=half)
+}
+
+/// Upload a single file to PocketBase, tagged with the inbox subfolder it came from.
+/// `on_progress` is called with (bytes sent, total bytes) as chunks are handed to the connection.
+async fn upload_file<F>(
+    path: &Path,
+    folder: Option<&str>,
+    server_url: &str,
+    on_progress: F,
+) -> Result<(), AppError>
+where
+    F: Fn(u64, u64) + Send + Sync + 'static,
+{
+    let token = auth::get_token().ok_or(AppError::NotAuthenticated)?;
+    let user_id = auth::get_user_id().ok_or(AppError::NotAuthenticated)?;
 
     let file_name = path
         .file_name()
@@ -297,27 +962,42 @@ async fn upload_file(path: &PathBuf, server_url: &str) -> Result<(), String> {
         .to_string_lossy()
         .to_string();
 
-    // Read file bytes
-    let file_bytes = tokio::fs::read(path)
+    // Open the file and stream it from disk so memory use stays bounded by the chunk size
+    let file = tokio::fs::File::open(path)
         .await
-        .map_err(|e| format!("Failed to read file: {}", e))?;
+        .map_err(|e| AppError::Io(e.to_string()))?;
+    let file_size = file
+        .metadata()
+        .await
+        .map_err(|e| AppError::Io(e.to_string()))?
+        .len();
+    let mut bytes_sent = 0u64;
+    on_progress(bytes_sent, file_size);
+    let stream = ReaderStream::with_capacity(file, UPLOAD_CHUNK_SIZE).inspect_ok(move |chunk| {
+        bytes_sent += chunk.len() as u64;
+        on_progress(bytes_sent, file_size);
+    });
+    let body = reqwest::Body::wrap_stream(stream);
 
     // Determine MIME type
     let mime_type = mime_guess::from_path(path)
         .first_or_octet_stream()
         .to_string();
 
-    // Build multipart form
-    let file_part = multipart::Part::bytes(file_bytes)
+    // Build multipart form; the known length keeps a proper Content-Length on the request
+    let file_part = multipart::Part::stream_with_length(body, file_size)
         .file_name(file_name.clone())
         .mime_str(&mime_type)
-        .map_err(|e| format!("Invalid MIME type: {}", e))?;
+        .map_err(AppError::from_reqwest)?;
 
-    let form = multipart::Form::new()
+    let mut form = multipart::Form::new()
         .part("file", file_part)
         .text("name", file_name)
         .text("user", user_id)
         .text("status", "pending".to_string());
+    if let Some(folder) = folder {
+        form = form.text("folder", folder.to_string());
+    }
 
     let url = format!(
         "{}/api/collections/files_inbox/records",
@@ -331,19 +1011,17 @@ async fn upload_file(path: &PathBuf, server_url: &str) -> Result<(), String> {
         .multipart(form)
         .send()
         .await
-        .map_err(|e| format!("Upload request failed: {}", e))?;
+        .map_err(AppError::from_reqwest)?;
 
     if response.status().is_success() {
         Ok(())
     } else {
-        let status = response.status();
-        let body = response.text().await.unwrap_or_default();
-        Err(format!("Upload failed ({}): {}", status, body))
+        Err(AppError::from_response(response).await)
     }
 }
 
-/// Check if the PocketBase server is reachable
-async fn check_server(server_url: &str) -> bool {
+/// Check if the PocketBase server is reachable and healthy
+pub async fn check_server(server_url: &str) -> Result<(), AppError> {
     let url = format!(
         "{}/api/health",
         server_url.trim_end_matches('/')
@@ -353,22 +1031,88 @@ async fn check_server(server_url: &str) -> bool {
         .build()
         .unwrap_or_default();
 
-    client.get(&url).send().await.is_ok()
+    let response = client.get(&url).send().await.map_err(AppError::from_reqwest)?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(AppError::from_response(response).await)
+    }
 }
 
-/// Convert raw error strings into user-friendly Spanish messages
-fn humanize_error(err: &str) -> String {
-    if err.contains("413") || err.contains("too large") || err.contains("payload") {
-        "El servidor rechazó el archivo por ser muy grande".to_string()
-    } else if err.contains("401") || err.contains("403") || err.contains("Not authenticated") {
-        "Sin autorización — cerrá sesión y volvé a ingresar".to_string()
-    } else if err.contains("timeout") || err.contains("timed out") {
-        "Tiempo de espera agotado — conexión lenta o servidor no responde".to_string()
-    } else if err.contains("connection") || err.contains("dns") || err.contains("resolve") {
-        "Error de conexión — verificá tu internet".to_string()
-    } else if err.contains("Failed to read file") {
-        "No se pudo leer el archivo".to_string()
-    } else {
-        format!("Error: {}", err)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh folder for one test, holding the journal and the inbox
+    fn test_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("inbox-uploader-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn interrupted_upload_is_queued_first_without_counting_a_retry() {
+        let dir = test_dir();
+        let manager = UploadManager::new(QueueJournal::new(&dir));
+        manager.enqueue(&dir, dir.join("a.pdf"));
+        manager.enqueue(&dir, dir.join("b.pdf"));
+
+        let item = manager.take_next_ready().unwrap();
+        assert_eq!(manager.active_count(), 1);
+        manager.requeue_interrupted(item);
+
+        assert_eq!(manager.active_count(), 0);
+        let queue = lock(&manager.queue);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue[0].path, dir.join("a.pdf"));
+        assert_eq!(queue[0].retries, 0);
+        drop(queue);
+        assert!(manager
+            .get_recent()
+            .iter()
+            .all(|r| r.status == UploadStatus::Pending));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn journal_is_saved_on_flush() {
+        let dir = test_dir();
+        let manager = UploadManager::new(QueueJournal::new(&dir));
+        for name in ["a.pdf", "b.pdf", "c.pdf"] {
+            std::fs::write(dir.join(name), b"x").unwrap();
+            manager.enqueue(&dir, dir.join(name));
+        }
+        // Changes are only marked; the journal writer isn't running here
+        assert_eq!(QueueJournal::new(&dir).load().queue.len(), 0);
+
+        manager.flush_journal();
+        let restored = UploadManager::new(QueueJournal::new(&dir));
+        assert_eq!(restored.queue_size(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejected_after_refresh_pauses_for_login() {
+        let dir = test_dir();
+        let manager = UploadManager::new(QueueJournal::new(&dir));
+        manager.enqueue(&dir, dir.join("a.pdf"));
+        let item = manager.take_next_ready().unwrap();
+
+        let result = after_refresh(Err(AppError::AuthExpired));
+        assert!(matches!(result, Err(AppError::NotAuthenticated)));
+        manager.pause_for_login(item);
+
+        assert!(manager.needs_login());
+        assert_eq!(manager.active_count(), 0);
+        assert_eq!(manager.queue_size(), 1);
+        assert_eq!(lock(&manager.queue)[0].retries, 0);
+        let recent = manager.get_recent();
+        assert_eq!(recent[0].status, UploadStatus::Pending);
+        assert_eq!(recent[0].error_kind, Some(ErrorKind::Auth));
+
+        // Anything else from the second attempt is handled as usual
+        assert!(after_refresh(Ok(())).is_ok());
+        assert!(matches!(after_refresh(Err(AppError::Forbidden)), Err(AppError::Forbidden)));
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,240 @@
+use crate::config::{self, AppConfig};
+use crate::error::FieldError;
+use crate::uploader;
+use crate::watcher;
+use reqwest::Url;
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/// Upper bound for `max_concurrent_uploads`
+pub const MAX_CONCURRENT_UPLOADS: usize = 8;
+
+/// Upper bound for `file_settle_secs`
+pub const MAX_FILE_SETTLE_SECS: u64 = 300;
+
+/// Upper bound for `poll_interval_secs`
+pub const MAX_POLL_INTERVAL_SECS: u64 = 600;
+
+/// File created and removed to check that the inbox folder is writable.
+/// Starts with a dot so the watcher ignores it.
+const WRITE_PROBE_NAME: &str = ".inmobiliaria-write-test";
+
+/// Check the config without touching the network. Returns one error per invalid field.
+pub fn validate(config: &AppConfig) -> Vec<FieldError> {
+    let mut errors = validate_values(config);
+    if !errors.iter().any(|e| e.field == "inbox_path") {
+        if let Err(e) = check_inbox_folder(Path::new(&config.inbox_path)) {
+            errors.push(e);
+        }
+    }
+    errors
+}
+
+/// The checks that don't depend on this computer's folders, so a share that isn't connected
+/// yet doesn't make a value invalid
+fn validate_values(config: &AppConfig) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    if let Err(e) = check_url("server_url", &config.server_url) {
+        errors.push(e);
+    }
+    // The web URL is optional; the tray falls back to the server URL
+    if !config.web_url.is_empty() {
+        if let Err(e) = check_url("web_url", &config.web_url) {
+            errors.push(e);
+        }
+    }
+    if let Err(e) = check_inbox_path(&config.inbox_path) {
+        errors.push(e);
+    }
+    if !(1..=MAX_CONCURRENT_UPLOADS).contains(&config.max_concurrent_uploads) {
+        errors.push(field_error(
+            "max_concurrent_uploads",
+            "out_of_range",
+            format!("Tiene que ser entre 1 y {}", MAX_CONCURRENT_UPLOADS),
+        ));
+    }
+    if !(1..=MAX_FILE_SETTLE_SECS).contains(&config.file_settle_secs) {
+        errors.push(field_error(
+            "file_settle_secs",
+            "out_of_range",
+            format!("Tiene que ser entre 1 y {} segundos", MAX_FILE_SETTLE_SECS),
+        ));
+    }
+    if !(1..=MAX_POLL_INTERVAL_SECS).contains(&config.poll_interval_secs) {
+        errors.push(field_error(
+            "poll_interval_secs",
+            "out_of_range",
+            format!("Tiene que ser entre 1 y {} segundos", MAX_POLL_INTERVAL_SECS),
+        ));
+    }
+    if let Err(e) = check_ignore_patterns(&config.ignore_patterns) {
+        errors.push(e);
+    }
+    errors
+}
+
+/// Remove the values in `fields` that don't fit their field or are out of range, so a bad
+/// policy or override value is never applied. `source` names them in the log.
+pub fn drop_invalid_values(fields: &mut Map<String, Value>, source: &str) {
+    let (candidate, rejected) = config::merge_fields(&AppConfig::default(), fields.clone());
+    for key in rejected {
+        log::warn!("Ignoring invalid {} for {}", source, key);
+        fields.remove(&key);
+    }
+    for error in validate_values(&candidate) {
+        if fields.remove(&error.field).is_some() {
+            log::warn!("Ignoring invalid {} for {}: {}", source, error.field, error.message);
+        }
+    }
+}
+
+/// Make sure PocketBase answers on `/api/health`
+pub async fn check_server_health(server_url: &str) -> Result<(), FieldError> {
+    uploader::check_server(server_url).await.map_err(|e| {
+        log::warn!("Health check for {} failed: {}", server_url, e);
+        field_error(
+            "server_url",
+            "unreachable",
+            format!("No se pudo conectar con el servidor: {}", e.user_message()),
+        )
+    })
+}
+
+fn check_url(field: &str, value: &str) -> Result<(), FieldError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(field_error(field, "required", "Ingresá una URL".to_string()));
+    }
+    if !value.contains("://") {
+        return Err(field_error(
+            field,
+            "missing_scheme",
+            "Falta http:// o https:// al comienzo".to_string(),
+        ));
+    }
+    let url = Url::parse(value)
+        .map_err(|_| field_error(field, "invalid_url", "La URL no es válida".to_string()))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(field_error(
+            field,
+            "invalid_scheme",
+            "La URL tiene que empezar con http:// o https://".to_string(),
+        ));
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err(field_error(field, "invalid_url", "La URL no tiene servidor".to_string()));
+    }
+    Ok(())
+}
+
+/// The inbox must be a full path
+fn check_inbox_path(value: &str) -> Result<(), FieldError> {
+    const FIELD: &str = "inbox_path";
+    if value.trim().is_empty() {
+        return Err(field_error(FIELD, "required", "Elegí una carpeta".to_string()));
+    }
+    let path = Path::new(value);
+    if !path.is_absolute() {
+        return Err(field_error(
+            FIELD,
+            "not_absolute",
+            "La ruta de la carpeta tiene que ser completa".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The inbox must be a writable folder, or a path where one can be created
+fn check_inbox_folder(path: &Path) -> Result<(), FieldError> {
+    const FIELD: &str = "inbox_path";
+    if path.exists() && !path.is_dir() {
+        return Err(field_error(
+            FIELD,
+            "not_a_directory",
+            "La ruta apunta a un archivo, no a una carpeta".to_string(),
+        ));
+    }
+
+    // The folder is created on demand, so check the closest folder that already exists
+    let existing = path.ancestors().find(|p| p.exists());
+    let writable = match existing {
+        Some(dir) if dir.is_dir() => is_writable(dir),
+        _ => false,
+    };
+    if !writable {
+        return Err(field_error(
+            FIELD,
+            "not_writable",
+            "No hay permiso para escribir en esa carpeta".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(WRITE_PROBE_NAME);
+    match std::fs::write(&probe, b"") {
+        Ok(()) => {
+            let _ = std::fs::remove_file(&probe);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Check the extra ignore patterns, also used by the preview before saving
+pub fn check_ignore_patterns(patterns: &[String]) -> Result<(), FieldError> {
+    watcher::check_ignore_patterns(patterns).map_err(|pattern| {
+        field_error(
+            "ignore_patterns",
+            "invalid_pattern",
+            format!("Patrón inválido: {}", pattern),
+        )
+    })
+}
+
+fn field_error(field: &str, code: &str, message: String) -> FieldError {
+    FieldError {
+        field: field.to_string(),
+        code: Some(code.to_string()),
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_policy_and_override_values_are_dropped() {
+        let mut fields = match serde_json::json!({
+            "poll_interval_secs": 0,
+            "max_concurrent_uploads": 500,
+            "file_settle_secs": "soon",
+            "server_url": "pb.example.com",
+            "inbox_path": "relative/inbox",
+            "delete_after_upload": false,
+            "web_url": "https://example.com",
+        }) {
+            Value::Object(fields) => fields,
+            _ => unreachable!(),
+        };
+
+        drop_invalid_values(&mut fields, "override");
+        let mut kept: Vec<&str> = fields.keys().map(String::as_str).collect();
+        kept.sort();
+        assert_eq!(kept, vec!["delete_after_upload", "web_url"]);
+    }
+
+    #[test]
+    fn missing_inbox_share_is_not_an_invalid_value() {
+        let share = std::env::temp_dir().join(format!("share-{}", uuid::Uuid::new_v4()));
+        let mut fields = Map::new();
+        fields.insert(
+            "inbox_path".to_string(),
+            Value::from(share.join("Inbox").to_string_lossy().to_string()),
+        );
+        drop_invalid_values(&mut fields, "locked policy value");
+        assert!(fields.contains_key("inbox_path"));
+    }
+}
@@ -1,92 +1,317 @@
-use notify::RecursiveMode;
-use notify_debouncer_mini::{new_debouncer, DebouncedEventKind};
+use crate::config::WatchMode;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use notify::{PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use notify_debouncer_mini::{
+    new_debouncer, new_debouncer_opt, DebounceEventResult, DebouncedEventKind, Debouncer,
+};
+use std::collections::HashMap;
+#[cfg(not(windows))]
+use std::fs::File;
+use std::fs::TryLockError;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-/// Files to ignore — system files, temp files, hidden files
-const IGNORED_EXACT: &[&str] = &[
-    ".DS_Store",
+/// Files ignored unless a later rule brings them back with `!pattern`: system files, temp
+/// files and hidden files. Same syntax as `.gitignore`.
+const DEFAULT_IGNORE_PATTERNS: &[&str] = &[
+    ".*",
     "Thumbs.db",
     "desktop.ini",
-    ".localized",
-    "Icon\r",
+    // The macOS custom folder icon, "Icon\r"
+    "Icon[\r]",
+    "~$*",
+    "*.tmp",
+    "*.swp",
+    "*.crdownload",
+    "*.part",
+    "*.partial",
 ];
 
-const IGNORED_PREFIXES: &[&str] = &["~$", "._"];
-
-const IGNORED_SUFFIXES: &[&str] = &[".tmp", ".swp", ".crdownload", ".part", ".partial"];
+/// Per-folder ignore rules, read from the inbox root
+pub const IGNORE_FILE_NAME: &str = ".inboxignore";
 
 /// Name of the "uploaded" subfolder (to ignore)
 const UPLOADED_FOLDER: &str = "Subidos";
 
-/// Check if a file should be ignored
-fn should_ignore(path: &Path) -> bool {
-    let file_name = match path.file_name().and_then(|n| n.to_str()) {
-        Some(name) => name,
-        None => return true,
-    };
+/// Decides which files in the inbox are not uploaded. Rules are applied in order: the built-in
+/// patterns, the `ignore_patterns` from the config and the `.inboxignore` file; the last one
+/// that matches wins, so `!pattern` brings back a file ignored by an earlier rule.
+pub struct IgnoreRules {
+    matcher: Gitignore,
+    root: PathBuf,
+    /// Modification time of `.inboxignore` when it was read
+    file_modified: Option<SystemTime>,
+    /// Whether files in subfolders are uploaded too
+    subfolders: bool,
+}
 
-    // Hidden files (start with .)
-    if file_name.starts_with('.') {
-        return true;
+impl IgnoreRules {
+    /// Build the rules for `inbox_path`. Invalid patterns are logged and skipped.
+    /// Without `subfolders`, only files directly in the inbox are uploaded.
+    pub fn load(inbox_path: &Path, patterns: &[String], subfolders: bool) -> Self {
+        let mut builder = GitignoreBuilder::new(inbox_path);
+        for line in DEFAULT_IGNORE_PATTERNS {
+            let _ = builder.add_line(None, line);
+        }
+        for line in patterns {
+            if let Err(e) = builder.add_line(None, line) {
+                log::warn!("Ignoring invalid ignore pattern '{}': {}", line, e);
+            }
+        }
+        let file = inbox_path.join(IGNORE_FILE_NAME);
+        let file_modified = modified_time(&file);
+        if file_modified.is_some() {
+            if let Some(e) = builder.add(&file) {
+                log::warn!("Problem reading {:?}: {}", file, e);
+            }
+        }
+        let matcher = builder.build().unwrap_or_else(|e| {
+            log::error!("Failed to build ignore rules: {}", e);
+            Gitignore::empty()
+        });
+        Self {
+            matcher,
+            root: inbox_path.to_path_buf(),
+            file_modified,
+            subfolders,
+        }
     }
 
-    // Exact matches
-    if IGNORED_EXACT.contains(&file_name) {
-        return true;
+    /// The rule that decides whether `path` is ignored, if any. Rules starting with `!` keep it.
+    pub fn matching_rule(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        match self.matcher.matched_path_or_any_parents(relative, false) {
+            Match::Ignore(glob) | Match::Whitelist(glob) => Some(glob.original().to_string()),
+            Match::None => None,
+        }
     }
 
-    // Prefix matches
-    if IGNORED_PREFIXES.iter().any(|p| file_name.starts_with(p)) {
-        return true;
+    /// Check if a file should be ignored
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let file_name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => return true,
+        };
+
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        let in_subfolder = relative.components().count() > 1;
+
+        // The rules file itself, uploaded files and directories can't be brought back
+        if file_name == IGNORE_FILE_NAME
+            || relative.starts_with(UPLOADED_FOLDER)
+            || (in_subfolder && !self.subfolders)
+            || path.is_dir()
+        {
+            return true;
+        }
+
+        self.matcher
+            .matched_path_or_any_parents(relative, false)
+            .is_ignore()
     }
 
-    // Suffix matches
-    if IGNORED_SUFFIXES.iter().any(|s| file_name.ends_with(s)) {
-        return true;
+    /// Whether `.inboxignore` was created, changed or removed since the rules were read
+    pub fn is_stale(&self) -> bool {
+        modified_time(&self.root.join(IGNORE_FILE_NAME)) != self.file_modified
     }
+}
 
-    // Ignore files inside the "Subidos" subfolder
-    if path
-        .components()
-        .any(|c| c.as_os_str() == UPLOADED_FOLDER)
-    {
-        return true;
+/// Check `ignore_patterns` from the config. Returns the first invalid pattern.
+pub fn check_ignore_patterns(patterns: &[String]) -> Result<(), String> {
+    let mut builder = GitignoreBuilder::new("");
+    for line in patterns {
+        if let Err(e) = builder.add_line(None, line) {
+            log::debug!("Invalid ignore pattern '{}': {}", line, e);
+            return Err(line.clone());
+        }
+    }
+    Ok(())
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Waits for files to finish arriving before they're uploaded. Pending files are polled until
+/// their size and modification time stay the same for `settle` and no other program has them
+/// open for writing or locked.
+pub struct ReadinessTracker {
+    settle: Duration,
+    pending: HashMap<PathBuf, Observation>,
+}
+
+struct Observation {
+    len: u64,
+    modified: Option<SystemTime>,
+    /// When the size or modification time last changed
+    changed_at: Instant,
+    /// Logged once, so a file left open in some program doesn't flood the log
+    reported_busy: bool,
+}
+
+impl ReadinessTracker {
+    pub fn new(settle: Duration) -> Self {
+        Self {
+            settle,
+            pending: HashMap::new(),
+        }
     }
 
-    // Ignore directories
-    if path.is_dir() {
-        return true;
+    /// Start waiting for `path`, or start over if it was already pending
+    pub fn track(&mut self, path: PathBuf) {
+        match std::fs::metadata(&path) {
+            Ok(meta) => {
+                self.pending.insert(
+                    path,
+                    Observation {
+                        len: meta.len(),
+                        modified: meta.modified().ok(),
+                        changed_at: Instant::now(),
+                        reported_busy: false,
+                    },
+                );
+            }
+            Err(_) => {
+                self.pending.remove(&path);
+            }
+        }
     }
 
-    false
+    pub fn is_pending(&self, path: &Path) -> bool {
+        self.pending.contains_key(path)
+    }
+
+    /// Check the pending files and return the ones that are ready to upload
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        let now = Instant::now();
+        let settle = self.settle;
+        let mut ready = Vec::new();
+        self.pending.retain(|path, seen| {
+            let meta = match std::fs::metadata(path) {
+                Ok(meta) if meta.is_file() => meta,
+                _ => {
+                    log::debug!("File went away before it was ready: {:?}", path);
+                    return false;
+                }
+            };
+            let modified = meta.modified().ok();
+            if meta.len() != seen.len || modified != seen.modified {
+                seen.len = meta.len();
+                seen.modified = modified;
+                seen.changed_at = now;
+                return true;
+            }
+            if now.duration_since(seen.changed_at) < settle {
+                return true;
+            }
+            // Some programs create the file first and fill it later; that write is a new event
+            if meta.len() == 0 {
+                log::debug!("Skipping empty file: {:?}", path);
+                return false;
+            }
+            if is_in_use(path) {
+                if !seen.reported_busy {
+                    log::info!("Waiting for another program to close {:?}", path);
+                    seen.reported_busy = true;
+                }
+                return true;
+            }
+            ready.push(path.clone());
+            false
+        });
+        ready
+    }
 }
 
-/// Check if a file is ready (not being written to) by checking size stability
-fn is_file_ready(path: &Path) -> bool {
-    let first_size = match std::fs::metadata(path) {
-        Ok(m) => m.len(),
-        Err(_) => return false,
-    };
+/// Whether another program still has the file open for writing or locked
+#[cfg(windows)]
+fn is_in_use(path: &Path) -> bool {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_SHARE_READ: u32 = 0x1;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
 
-    std::thread::sleep(Duration::from_millis(500));
+    // Refusing to share write access fails while a writer has the file open
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .share_mode(FILE_SHARE_READ)
+        .open(path)
+    {
+        Ok(file) => matches!(file.try_lock_shared(), Err(TryLockError::WouldBlock)),
+        Err(e) => matches!(
+            e.raw_os_error(),
+            Some(ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+        ),
+    }
+}
 
-    let second_size = match std::fs::metadata(path) {
-        Ok(m) => m.len(),
-        Err(_) => return false,
-    };
+/// Whether another program still holds a lock on the file. Unix has no share modes, so
+/// files open without a lock are caught by the size and modification time checks.
+#[cfg(not(windows))]
+fn is_in_use(path: &Path) -> bool {
+    match File::open(path) {
+        Ok(file) => matches!(file.try_lock_shared(), Err(TryLockError::WouldBlock)),
+        Err(_) => false,
+    }
+}
 
-    first_size == second_size && first_size > 0
+/// Identifies a folder on disk, to notice when a path starts pointing at a different one:
+/// deleted and created again, or on a drive or network share that was mounted again
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FolderId(u64, u64);
+
+impl FolderId {
+    /// Whether `path` is on the same drive or mount as this folder. Always true where the
+    /// device can't be told.
+    pub fn is_same_device(&self, path: &Path) -> bool {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            std::fs::metadata(path).is_ok_and(|m| m.dev() == self.0)
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = path;
+            true
+        }
+    }
 }
 
-/// Scan existing files in the inbox folder (for files that arrived while offline)
-pub fn scan_existing_files(inbox_path: &Path) -> Vec<PathBuf> {
+/// Identity of the folder at `path`, or `None` if there is no folder there
+pub fn folder_id(path: &Path) -> Option<FolderId> {
+    let meta = std::fs::metadata(path).ok().filter(|m| m.is_dir())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some(FolderId(meta.dev(), meta.ino()))
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        Some(FolderId(0, meta.creation_time()))
+    }
+}
+
+/// Every file in the inbox folder, ignored or not. With `recursive`, files in subfolders are
+/// listed too, except in "Subidos"; links to folders are not followed.
+pub fn list_files(inbox_path: &Path, recursive: bool) -> Vec<PathBuf> {
     let mut files = Vec::new();
-    if let Ok(entries) = std::fs::read_dir(inbox_path) {
+    let mut folders = vec![inbox_path.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        let Ok(entries) = std::fs::read_dir(&folder) else {
+            continue;
+        };
         for entry in entries.flatten() {
             let path = entry.path();
-            if !should_ignore(&path) && path.is_file() {
+            let is_folder = entry.file_type().is_ok_and(|t| t.is_dir());
+            if is_folder {
+                let uploaded = folder == inbox_path && entry.file_name() == UPLOADED_FOLDER;
+                if recursive && !uploaded {
+                    folders.push(path);
+                }
+            } else if path.is_file() {
                 files.push(path);
             }
         }
@@ -94,59 +319,460 @@ pub fn scan_existing_files(inbox_path: &Path) -> Vec<PathBuf> {
     files
 }
 
+/// Scan existing files in the inbox folder (for files that arrived while offline)
+pub fn scan_existing_files(inbox_path: &Path, rules: &IgnoreRules) -> Vec<PathBuf> {
+    list_files(inbox_path, rules.subfolders)
+        .into_iter()
+        .filter(|path| !rules.is_ignored(path))
+        .collect()
+}
+
+/// `path` relative to the inbox, with `/` between folders on every platform,
+/// e.g. `Facturas/enero.pdf`
+pub fn inbox_relative(inbox_path: &Path, path: &Path) -> String {
+    path.strip_prefix(inbox_path)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Files the rules don't ignore in `folder`, a subfolder of the inbox that was just created or
+/// moved in
+pub fn scan_folder(folder: &Path, rules: &IgnoreRules) -> Vec<PathBuf> {
+    let relative = folder.strip_prefix(&rules.root).unwrap_or(folder);
+    if !rules.subfolders || relative.starts_with(UPLOADED_FOLDER) {
+        return Vec::new();
+    }
+    list_files(folder, true)
+        .into_iter()
+        .filter(|path| !rules.is_ignored(path))
+        .collect()
+}
+
+/// How long events are collected before the changed files are reported
+const DEBOUNCE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Filesystems where changes made by other machines don't produce OS events
+#[cfg(target_os = "linux")]
+const NETWORK_FILESYSTEMS: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "ncpfs",
+    "afs",
+    "9p",
+    "ceph",
+    "glusterfs",
+    "fuse.sshfs",
+    "fuse.rclone",
+    "fuse.davfs2",
+];
+
+/// Keeps the inbox folder watched until dropped
+pub struct InboxWatch {
+    debouncer: InboxDebouncer,
+    /// Subfolders watched with everything in them; a folder created again under the same
+    /// name needs a new watch
+    subfolders: HashMap<PathBuf, FolderId>,
+}
+
+enum InboxDebouncer {
+    Native(Debouncer<RecommendedWatcher>),
+    Poll(Debouncer<PollWatcher>),
+}
+
+impl InboxWatch {
+    /// Watch a folder directly in the inbox and everything in it. The inbox itself is watched
+    /// on its own, so the uploaded files in "Subidos" don't cost a watch or a scan.
+    pub fn watch_subfolder(&mut self, path: &Path) {
+        if path.file_name().is_some_and(|n| n == UPLOADED_FOLDER) {
+            return;
+        }
+        let Some(id) = folder_id(path) else {
+            return;
+        };
+        if self.subfolders.get(path) == Some(&id) {
+            return;
+        }
+        let watcher: &mut dyn Watcher = match &mut self.debouncer {
+            InboxDebouncer::Native(debouncer) => debouncer.watcher(),
+            InboxDebouncer::Poll(debouncer) => debouncer.watcher(),
+        };
+        match watcher.watch(path, RecursiveMode::Recursive) {
+            Ok(()) => {
+                log::debug!("Watching subfolder: {:?}", path);
+                self.subfolders.insert(path.to_path_buf(), id);
+            }
+            Err(e) => log::warn!("Failed to watch subfolder {:?}: {}", path, e),
+        }
+    }
+}
+
 /// Start watching the inbox folder for new/changed files.
-/// Returns a channel receiver that emits file paths when new files are detected.
+/// Returns a channel receiver that emits file paths when new files are detected; they may
+/// still be being written, see `ReadinessTracker`.
 /// Also returns the watcher handle (must be kept alive).
+///
+/// With `WatchMode::Auto`, folders on a network share are polled every `poll_interval`,
+/// since other machines' changes don't reach the OS events there. With `recursive`, the
+/// subfolders there now are watched too; new ones are added with `watch_subfolder`.
 pub fn start_watching(
     inbox_path: &Path,
-) -> Result<
-    (
-        mpsc::Receiver<PathBuf>,
-        notify_debouncer_mini::Debouncer<notify::RecommendedWatcher>,
-    ),
-    String,
-> {
+    mode: WatchMode,
+    poll_interval: Duration,
+    recursive: bool,
+) -> Result<(mpsc::Receiver<PathBuf>, InboxWatch), String> {
     let (tx, rx) = mpsc::channel::<PathBuf>();
 
-    let tx_clone = tx.clone();
-    let inbox_path_owned = inbox_path.to_path_buf();
+    let poll = match mode {
+        WatchMode::Native => false,
+        WatchMode::Poll => true,
+        WatchMode::Auto => is_network_path(inbox_path),
+    };
+    let debouncer = if poll {
+        let config = notify_debouncer_mini::Config::default()
+            .with_timeout(DEBOUNCE_TIMEOUT)
+            .with_notify_config(notify::Config::default().with_poll_interval(poll_interval));
+        let debouncer = watch_folder(
+            new_debouncer_opt::<_, PollWatcher>(config, event_handler(tx, inbox_path, recursive)),
+            inbox_path,
+        )?;
+        log::info!("Polling folder every {:?}: {:?}", poll_interval, inbox_path);
+        InboxDebouncer::Poll(debouncer)
+    } else {
+        let debouncer = watch_folder(
+            new_debouncer(DEBOUNCE_TIMEOUT, event_handler(tx, inbox_path, recursive)),
+            inbox_path,
+        )?;
+        log::info!("Watching folder: {:?}", inbox_path);
+        InboxDebouncer::Native(debouncer)
+    };
 
-    let mut debouncer = new_debouncer(Duration::from_secs(2), move |result: Result<Vec<notify_debouncer_mini::DebouncedEvent>, notify::Error>| {
-        match result {
-            Ok(events) => {
-                for event in events {
-                    if event.kind == DebouncedEventKind::Any {
-                        let path = event.path;
-                        // Only process files directly in the inbox (not subdirectories' contents will be filtered by should_ignore)
-                        if !should_ignore(&path) && path.is_file() {
-                            // Check if file is inside the watched inbox directory (not a subdirectory situation)
-                            if let Some(parent) = path.parent() {
-                                if parent == inbox_path_owned {
-                                    if is_file_ready(&path) {
-                                        log::info!("New file detected: {:?}", path);
-                                        let _ = tx_clone.send(path);
-                                    } else {
-                                        log::debug!("File not ready yet: {:?}", path);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                log::error!("Watcher error: {:?}", e);
+    let mut watch = InboxWatch {
+        debouncer,
+        subfolders: HashMap::new(),
+    };
+    if recursive {
+        for entry in std::fs::read_dir(inbox_path).into_iter().flatten().flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                watch.watch_subfolder(&entry.path());
             }
         }
-    })
-    .map_err(|e| format!("Failed to create file watcher: {}", e))?;
+    }
+    Ok((rx, watch))
+}
 
+fn watch_folder<T: Watcher>(
+    debouncer: Result<Debouncer<T>, notify::Error>,
+    path: &Path,
+) -> Result<Debouncer<T>, String> {
+    let mut debouncer = debouncer.map_err(|e| format!("Failed to create file watcher: {}", e))?;
     debouncer
         .watcher()
-        .watch(inbox_path, RecursiveMode::NonRecursive)
+        .watch(path, RecursiveMode::NonRecursive)
         .map_err(|e| format!("Failed to watch folder: {}", e))?;
+    Ok(debouncer)
+}
+
+/// Send the files that changed in the inbox to `tx`; with `recursive`, the files and folders
+/// that changed in its subfolders too
+fn event_handler(
+    tx: mpsc::Sender<PathBuf>,
+    inbox_path: &Path,
+    recursive: bool,
+) -> impl FnMut(DebounceEventResult) + Send + 'static {
+    let inbox_path_owned = inbox_path.to_path_buf();
+    move |result: DebounceEventResult| match result {
+        Ok(events) => {
+            for event in events {
+                if event.kind == DebouncedEventKind::Any {
+                    let path = event.path;
+                    // Ignore rules are applied by the receiver, since `.inboxignore` may change
+                    // while watching
+                    let watched = if recursive {
+                        // Folders are sent too: one moved in at once may only report itself
+                        path.starts_with(&inbox_path_owned)
+                            && path != inbox_path_owned
+                            && (path.is_file() || path.is_dir())
+                    } else {
+                        path.is_file() && path.parent() == Some(inbox_path_owned.as_path())
+                    };
+                    if watched {
+                        log::debug!("File changed: {:?}", path);
+                        let _ = tx.send(path);
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            log::error!("Watcher error: {:?}", e);
+        }
+    }
+}
+
+/// Whether a drive or share is mounted at `path`, or is meant to be: it's on a different device
+/// than its parent folder, or it's listed in `/etc/fstab`. The empty folder left by a share
+/// that isn't connected is on its parent's device, so only the second check catches that.
+pub fn is_mount_point(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let parent = path.parent().map(std::fs::metadata);
+        if let (Ok(meta), Some(Ok(parent))) = (std::fs::metadata(path), parent) {
+            if meta.dev() != parent.dev() {
+                return true;
+            }
+        }
+    }
+    is_configured_mount_point(path)
+}
 
-    log::info!("Watching folder: {:?}", inbox_path);
+#[cfg(target_os = "linux")]
+fn is_configured_mount_point(path: &Path) -> bool {
+    let Ok(path) = path.canonicalize() else {
+        return false;
+    };
+    let Ok(fstab) = std::fs::read_to_string("/etc/fstab") else {
+        return false;
+    };
+    fstab
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .any(|mount_point| Path::new(&unescape_mount_path(mount_point)) == path)
+}
 
-    Ok((rx, debouncer))
+/// macOS creates and removes the folders in `/Volumes` with the mount, and a Windows drive
+/// that isn't connected has no root folder
+#[cfg(not(target_os = "linux"))]
+fn is_configured_mount_point(_path: &Path) -> bool {
+    false
+}
+
+/// Whether `path` is on a network filesystem. Unknown counts as local.
+#[cfg(target_os = "linux")]
+pub fn is_network_path(path: &Path) -> bool {
+    let Ok(path) = path.canonicalize() else {
+        return false;
+    };
+    let Ok(mounts) = std::fs::read_to_string("/proc/self/mounts") else {
+        return false;
+    };
+    // The mount point closest to the path decides
+    let mut best: Option<(PathBuf, &str)> = None;
+    for line in mounts.lines() {
+        let mut fields = line.split(' ');
+        let (Some(_), Some(mount_point), Some(fs_type)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let mount_point = PathBuf::from(unescape_mount_path(mount_point));
+        let closer = best
+            .as_ref()
+            .is_none_or(|(best, _)| mount_point.as_os_str().len() >= best.as_os_str().len());
+        if path.starts_with(&mount_point) && closer {
+            best = Some((mount_point, fs_type));
+        }
+    }
+    best.is_some_and(|(_, fs_type)| NETWORK_FILESYSTEMS.contains(&fs_type))
+}
+
+/// `/proc/self/mounts` writes spaces and some other characters as octal escapes (`\040`)
+#[cfg(target_os = "linux")]
+fn unescape_mount_path(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 4).and_then(|digits| {
+            let digits = std::str::from_utf8(digits).ok()?;
+            u8::from_str_radix(digits, 8).ok()
+        });
+        match escaped {
+            Some(byte) if bytes[i] == b'\\' => {
+                out.push(byte);
+                i += 4;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Whether `path` is on a network filesystem. Unknown counts as local.
+#[cfg(target_os = "macos")]
+pub fn is_network_path(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut stats = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: `c_path` is a valid C string and `stats` is only read if statfs filled it
+    if unsafe { libc::statfs(c_path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return false;
+    }
+    let stats = unsafe { stats.assume_init() };
+    stats.f_flags & libc::MNT_LOCAL as u32 == 0
+}
+
+/// Whether `path` is on a network filesystem. Unknown counts as local.
+#[cfg(windows)]
+pub fn is_network_path(path: &Path) -> bool {
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::{Component, Prefix};
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDriveTypeW(root_path_name: *const u16) -> u32;
+    }
+    const DRIVE_REMOTE: u32 = 4;
+
+    let root = match path.components().next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::UNC(..) | Prefix::VerbatimUNC(..) => return true,
+            _ => prefix.as_os_str().to_owned(),
+        },
+        _ => return false,
+    };
+    // A mapped drive letter, like `Z:\`
+    let root: Vec<u16> = root.encode_wide().chain("\\\0".encode_utf16()).collect();
+    // SAFETY: `root` is a NUL-terminated wide string
+    unsafe { GetDriveTypeW(root.as_ptr()) == DRIVE_REMOTE }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn is_network_path(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh inbox folder for one test
+    fn test_inbox() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("inbox-watcher-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn patterns(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn last_matching_rule_wins() {
+        let inbox = test_inbox();
+        std::fs::write(inbox.join(IGNORE_FILE_NAME), "!important.pdf\nimportant-*.pdf\n").unwrap();
+        let rules = IgnoreRules::load(&inbox, &patterns(&["*.pdf", "!keep.tmp"]), true);
+
+        assert!(rules.is_ignored(&inbox.join("contract.pdf")));
+        // `.inboxignore` comes after the config, so it can bring back a file
+        assert!(!rules.is_ignored(&inbox.join("important.pdf")));
+        // ...and ignore it again with a later line
+        assert!(rules.is_ignored(&inbox.join("important-draft.pdf")));
+        // A config rule brings back a file ignored by the built-in patterns
+        assert!(!rules.is_ignored(&inbox.join("keep.tmp")));
+        assert!(rules.is_ignored(&inbox.join("other.tmp")));
+        assert_eq!(
+            rules.matching_rule(&inbox.join("important.pdf")).as_deref(),
+            Some("!important.pdf")
+        );
+        std::fs::remove_dir_all(&inbox).unwrap();
+    }
+
+    #[test]
+    fn uploaded_folder_cannot_be_brought_back() {
+        let inbox = test_inbox();
+        let rules = IgnoreRules::load(&inbox, &patterns(&["!Subidos", "!Subidos/**"]), true);
+
+        assert!(rules.is_ignored(&inbox.join(UPLOADED_FOLDER).join("photo.jpg")));
+        assert!(rules.is_ignored(&inbox.join(IGNORE_FILE_NAME)));
+        assert!(!rules.is_ignored(&inbox.join("photo.jpg")));
+        std::fs::remove_dir_all(&inbox).unwrap();
+    }
+
+    #[test]
+    fn subfolders_can_be_turned_off() {
+        let inbox = test_inbox();
+        let file = inbox.join("Casa 12").join("plano.pdf");
+
+        let rules = IgnoreRules::load(&inbox, &[], false);
+        assert!(rules.is_ignored(&file));
+        assert!(!rules.is_ignored(&inbox.join("plano.pdf")));
+
+        let rules = IgnoreRules::load(&inbox, &[], true);
+        assert!(!rules.is_ignored(&file));
+        std::fs::remove_dir_all(&inbox).unwrap();
+    }
+
+    const SETTLE: Duration = Duration::from_millis(200);
+
+    #[test]
+    fn file_is_ready_once_it_stops_changing() {
+        let inbox = test_inbox();
+        let path = inbox.join("plano.pdf");
+        std::fs::write(&path, b"first part").unwrap();
+        let mut pending = ReadinessTracker::new(SETTLE);
+        pending.track(path.clone());
+        assert!(pending.poll().is_empty());
+
+        // Still being written: the wait starts over
+        std::thread::sleep(SETTLE / 2);
+        std::fs::write(&path, b"first part, second part").unwrap();
+        assert!(pending.poll().is_empty());
+        std::thread::sleep(SETTLE / 2 + SETTLE / 4);
+        assert!(pending.poll().is_empty());
+        assert!(pending.is_pending(&path));
+
+        std::thread::sleep(SETTLE);
+        assert_eq!(pending.poll(), vec![path.clone()]);
+        assert!(!pending.is_pending(&path));
+        std::fs::remove_dir_all(&inbox).unwrap();
+    }
+
+    #[test]
+    fn empty_or_removed_file_is_dropped() {
+        let inbox = test_inbox();
+        let empty = inbox.join("vacío.pdf");
+        let removed = inbox.join("borrado.pdf");
+        std::fs::write(&empty, b"").unwrap();
+        std::fs::write(&removed, b"x").unwrap();
+        let mut pending = ReadinessTracker::new(Duration::ZERO);
+        pending.track(empty.clone());
+        pending.track(removed.clone());
+        std::fs::remove_file(&removed).unwrap();
+
+        assert!(pending.poll().is_empty());
+        assert!(!pending.is_pending(&empty));
+        assert!(!pending.is_pending(&removed));
+        std::fs::remove_dir_all(&inbox).unwrap();
+    }
+
+    #[test]
+    fn locked_file_waits_until_released() {
+        let inbox = test_inbox();
+        let path = inbox.join("contrato.pdf");
+        std::fs::write(&path, b"x").unwrap();
+        let mut pending = ReadinessTracker::new(Duration::ZERO);
+        pending.track(path.clone());
+
+        let writer = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        writer.lock().unwrap();
+        assert!(pending.poll().is_empty());
+        assert!(pending.is_pending(&path));
+
+        drop(writer);
+        assert_eq!(pending.poll(), vec![path.clone()]);
+        std::fs::remove_dir_all(&inbox).unwrap();
+    }
 }
@@ -1,10 +1,12 @@
 const { invoke } = window.__TAURI__.core;
+const { listen } = window.__TAURI__.event;
 
 // ---- DOM Elements ----
 const viewLogin = document.getElementById("view-login");
 const viewSettings = document.getElementById("view-settings");
 const loginForm = document.getElementById("login-form");
 const loginError = document.getElementById("login-error");
+const settingsError = document.getElementById("settings-error");
 const btnLogin = document.getElementById("btn-login");
 const btnSave = document.getElementById("btn-save");
 const btnLogout = document.getElementById("btn-logout");
@@ -12,24 +14,87 @@ const btnOpenFolder = document.getElementById("btn-open-folder");
 const btnChangeFolder = document.getElementById("btn-change-folder");
 const toggleAutostart = document.getElementById("toggle-autostart");
 const toggleDelete = document.getElementById("toggle-delete");
+const toggleSubfolders = document.getElementById("toggle-subfolders");
+const selectConcurrency = document.getElementById("select-concurrency");
+const selectSettle = document.getElementById("select-settle");
+const selectWatchMode = document.getElementById("select-watch-mode");
+const selectPollInterval = document.getElementById("select-poll-interval");
+const ignorePatterns = document.getElementById("ignore-patterns");
+const btnPreviewIgnore = document.getElementById("btn-preview-ignore");
+const btnExport = document.getElementById("btn-export");
+const btnImport = document.getElementById("btn-import");
+const backupMessage = document.getElementById("backup-message");
+
+// Controls for the config fields an admin policy or a launch option can lock
+const lockableControls = {
+    server_url: "server-url",
+    web_url: "web-url",
+    inbox_path: "btn-change-folder",
+    delete_after_upload: "toggle-delete",
+    auto_start: "toggle-autostart",
+    watch_subfolders: "toggle-subfolders",
+    max_concurrent_uploads: "select-concurrency",
+    file_settle_secs: "select-settle",
+    watch_mode: "select-watch-mode",
+    poll_interval_secs: "select-poll-interval",
+    ignore_patterns: "ignore-patterns",
+};
 
 // ---- State ----
 let currentConfig = null;
 
 // ---- Initialize ----
 async function init() {
+    await applyLockedFields();
+
     try {
-        const auth = await invoke("check_auth");
-        if (auth) {
+        const session = await invoke("check_auth");
+        if (isSessionUsable(session)) {
             await loadSettings();
+            renderSession(session);
             showView("settings");
+        } else {
+            showLogin(session);
         }
     } catch {
         showView("login");
     }
 
+    // "Iniciar sesión de nuevo" from the tray menu
+    listen("show-login", async () => {
+        try {
+            showLogin(await invoke("check_auth"));
+        } catch {
+            showView("login");
+        }
+    });
+
     // Start status polling
     setInterval(updateStatus, 5000);
+
+    // Live progress pushed by the upload worker
+    listen("upload-progress", (event) => renderProgress(event.payload));
+}
+
+// ---- Managed settings ----
+const lockedReason = {
+    policy: "Definido por el administrador",
+    override: "Fijado al iniciar (línea de comandos o variable de entorno)",
+};
+
+async function applyLockedFields() {
+    let locked = [];
+    try {
+        locked = await invoke("get_locked_fields");
+    } catch {
+        // No policy information, leave everything editable
+    }
+    for (const [field, id] of Object.entries(lockableControls)) {
+        const el = document.getElementById(id);
+        const lock = locked.find((l) => l.field === field);
+        el.disabled = lock !== undefined;
+        el.title = lock ? lockedReason[lock.source] : "";
+    }
 }
 
 // ---- View Management ----
@@ -39,9 +104,28 @@ function showView(view) {
 }
 
 // ---- Login ----
+function isSessionUsable(session) {
+    return session && (session.state === "valid" || session.state === "refreshable");
+}
+
+function showLogin(session) {
+    if (session && session.email) {
+        document.getElementById("email").value = session.email;
+    }
+    const reasons = {
+        expired: "Tu sesión venció. Ingresá tu contraseña para seguir subiendo archivos.",
+        revoked: "El servidor rechazó tu sesión. Volvé a iniciar sesión.",
+    };
+    const reason = session && reasons[session.state];
+    loginError.textContent = reason || "";
+    loginError.classList.toggle("hidden", !reason);
+    showView("login");
+}
+
 loginForm.addEventListener("submit", async (e) => {
     e.preventDefault();
     loginError.classList.add("hidden");
+    showFieldErrors(viewLogin, []);
 
     const serverUrl = document.getElementById("server-url").value.trim() || null;
     const webUrl = document.getElementById("web-url").value.trim() || null;
@@ -64,10 +148,16 @@ loginForm.addEventListener("submit", async (e) => {
         await invoke("start_services_cmd");
 
         await loadSettings();
+        renderSession(await invoke("check_auth"));
         showView("settings");
     } catch (err) {
-        loginError.textContent = typeof err === "string" ? err : "Error de conexión. Verificá la URL y las credenciales.";
+        loginError.textContent = errorMessage(err) || "Error de conexión. Verificá la URL y las credenciales.";
         loginError.classList.remove("hidden");
+        if (err && err.fields) {
+            // URL fields live under "Opciones avanzadas"
+            loginForm.querySelector(".advanced-toggle").open = true;
+            showFieldErrors(viewLogin, err.fields);
+        }
     } finally {
         setLoading(false);
     }
@@ -94,17 +184,42 @@ async function loadSettings() {
 
         toggleAutostart.checked = config.auto_start;
         toggleDelete.checked = config.delete_after_upload;
+        toggleSubfolders.checked = config.watch_subfolders;
+        // Overrides may allow more than the listed options
+        const concurrency = config.max_concurrent_uploads;
+        setSelectValue(selectConcurrency, concurrency, String(concurrency));
+        setSelectValue(selectSettle, config.file_settle_secs, `${config.file_settle_secs} s`);
+        selectWatchMode.value = config.watch_mode;
+        setSelectValue(selectPollInterval, config.poll_interval_secs, `${config.poll_interval_secs} s`);
+        ignorePatterns.value = config.ignore_patterns.join("\n");
 
-        // Load email from status
+        // Load email and config problems found at startup from status
         const status = await invoke("get_status");
         if (status.email) {
             document.getElementById("settings-email").textContent = status.email;
         }
+        showFieldErrors(viewSettings, status.config_errors);
     } catch (err) {
         console.error("Failed to load settings:", err);
     }
 }
 
+// Select `value`, adding it as an option if it was set outside the app (policy or config file)
+function setSelectValue(select, value, label) {
+    const text = String(value);
+    if (![...select.options].some((o) => o.value === text)) {
+        select.add(new Option(label, text));
+    }
+    select.value = text;
+}
+
+// Non-empty lines of the ignore patterns box
+function readIgnorePatterns() {
+    return ignorePatterns.value
+        .split("\n")
+        .filter((line) => line.trim() !== "");
+}
+
 // ---- Save Settings ----
 btnSave.addEventListener("click", async () => {
     if (!currentConfig) return;
@@ -113,8 +228,17 @@ btnSave.addEventListener("click", async () => {
         ...currentConfig,
         auto_start: toggleAutostart.checked,
         delete_after_upload: toggleDelete.checked,
+        watch_subfolders: toggleSubfolders.checked,
+        max_concurrent_uploads: Number(selectConcurrency.value),
+        file_settle_secs: Number(selectSettle.value),
+        watch_mode: selectWatchMode.value,
+        poll_interval_secs: Number(selectPollInterval.value),
+        ignore_patterns: readIgnorePatterns(),
     };
 
+    settingsError.classList.add("hidden");
+    showFieldErrors(viewSettings, []);
+
     try {
         await invoke("save_config", { config: newConfig });
         currentConfig = newConfig;
@@ -131,6 +255,9 @@ btnSave.addEventListener("click", async () => {
         }, 1500);
     } catch (err) {
         console.error("Failed to save config:", err);
+        settingsError.textContent = errorMessage(err) || "No se pudo guardar la configuración";
+        settingsError.classList.remove("hidden");
+        showFieldErrors(viewSettings, err && err.fields);
     }
 });
 
@@ -174,6 +301,103 @@ btnChangeFolder.addEventListener("click", async () => {
     }
 });
 
+// ---- Ignore Rules Preview ----
+btnPreviewIgnore.addEventListener("click", async () => {
+    const list = document.getElementById("ignore-preview");
+    showFieldErrors(viewSettings, []);
+    try {
+        const entries = await invoke("preview_ignore_rules", { patterns: readIgnorePatterns() });
+        list.classList.remove("hidden");
+        if (entries.length === 0) {
+            const empty = document.createElement("div");
+            empty.className = "progress-detail";
+            empty.textContent = "La carpeta está vacía";
+            list.replaceChildren(empty);
+            return;
+        }
+        list.replaceChildren(...entries.map((entry) => {
+            const item = document.createElement("div");
+            item.className = "preview-item" + (entry.ignored ? " ignored" : "");
+
+            const name = document.createElement("span");
+            name.className = "truncate";
+            name.textContent = entry.name;
+            const verdict = document.createElement("span");
+            verdict.textContent = entry.ignored ? "Ignorado" : "Se sube";
+            if (entry.rule) verdict.title = entry.rule;
+            item.append(name, verdict);
+            return item;
+        }));
+    } catch (err) {
+        console.error("Failed to preview ignore rules:", err);
+        list.classList.add("hidden");
+        showFieldErrors(viewSettings, err && err.fields);
+    }
+});
+
+// ---- Backup ----
+function showBackupMessage(text) {
+    backupMessage.textContent = text;
+    backupMessage.classList.remove("hidden");
+}
+
+btnExport.addEventListener("click", async () => {
+    try {
+        const path = await invoke("export_settings");
+        if (path) showBackupMessage(`Respaldo guardado en ${shortenPath(path)}`);
+    } catch (err) {
+        console.error("Failed to export settings:", err);
+        showBackupMessage(errorMessage(err) || "No se pudo exportar la configuración");
+    }
+});
+
+btnImport.addEventListener("click", async () => {
+    try {
+        const summary = await invoke("import_settings");
+        if (!summary) return;
+
+        await loadSettings();
+        // The bundle may turn autostart on or off
+        try {
+            await invoke("set_autostart", { enabled: currentConfig.auto_start });
+        } catch { }
+
+        let text = `Importado: ${summary.applied.length} ajuste${summary.applied.length === 1 ? "" : "s"}`
+            + ` y ${summary.history} archivo${summary.history === 1 ? "" : "s"} del historial.`;
+        if (summary.inbox_ignore_backup) {
+            text += " Se restauró .inboxignore en la carpeta Inbox (las reglas anteriores quedaron en .inboxignore.bak).";
+        } else if (summary.inbox_ignore) {
+            text += " Se restauró .inboxignore en la carpeta Inbox.";
+        }
+        if (summary.skipped.length > 0) {
+            const fields = summary.skipped.map((e) => `${e.field} (${e.message})`).join(", ");
+            text += ` Sin cambios: ${fields}.`;
+        }
+        showBackupMessage(text);
+    } catch (err) {
+        console.error("Failed to import settings:", err);
+        showBackupMessage(errorMessage(err) || "No se pudo importar la configuración");
+    }
+});
+
+// ---- Session ----
+function renderSession(auth) {
+    const el = document.getElementById("settings-session");
+    const secs = auth.expires_in_secs;
+    if (secs == null) {
+        el.textContent = "—";
+    } else if (secs <= 0) {
+        el.textContent = "Vencida";
+    } else if (secs < 3600) {
+        el.textContent = `Vence en ${Math.ceil(secs / 60)} min`;
+    } else if (secs < 86400) {
+        el.textContent = `Vence en ${Math.floor(secs / 3600)} h`;
+    } else {
+        const days = Math.floor(secs / 86400);
+        el.textContent = `Vence en ${days} día${days > 1 ? "s" : ""}`;
+    }
+}
+
 // ---- Status Updates ----
 async function updateStatus() {
     try {
@@ -198,12 +422,90 @@ async function updateStatus() {
         queueCount.textContent = count === 0
             ? "Sin archivos pendientes"
             : `${count} archivo${count > 1 ? "s" : ""} pendiente${count > 1 ? "s" : ""}`;
+
+        renderReconcile(status.reconcile);
+        renderProgress(status.progress);
     } catch {
         // View might not be active
     }
 }
 
+// Periodic scan for files the watcher missed
+function renderReconcile(reconcile) {
+    const el = document.getElementById("reconcile-status");
+    if (!reconcile || !reconcile.last_run_at) {
+        el.textContent = "—";
+        return;
+    }
+    const time = new Date(reconcile.last_run_at).toLocaleTimeString([], { hour: "2-digit", minute: "2-digit" });
+    const total = reconcile.total_recovered;
+    el.textContent = total === 0
+        ? time
+        : `${time} · ${total} archivo${total > 1 ? "s" : ""} recuperado${total > 1 ? "s" : ""}`;
+    el.title = "Archivos que se encontraron en la carpeta sin haber sido detectados";
+}
+
+function renderProgress(progress) {
+    const list = document.getElementById("upload-progress");
+    list.classList.toggle("hidden", !progress || progress.length === 0);
+    list.replaceChildren(...(progress || []).map((p) => {
+        const item = document.createElement("div");
+        item.className = "progress-item";
+
+        const name = document.createElement("div");
+        name.className = "progress-name";
+        const label = document.createElement("span");
+        label.className = "truncate";
+        label.textContent = p.name;
+        const percent = document.createElement("span");
+        percent.textContent = `${p.percent}%`;
+        name.append(label, percent);
+
+        const bar = document.createElement("div");
+        bar.className = "progress-bar";
+        const fill = document.createElement("div");
+        fill.className = "progress-fill";
+        fill.style.width = `${p.percent}%`;
+        bar.append(fill);
+
+        const detail = document.createElement("div");
+        detail.className = "progress-detail";
+        detail.textContent = `${formatBytes(p.bytes_sent)} de ${formatBytes(p.total_bytes)} · ${formatBytes(p.bytes_per_sec)}/s`
+            + (p.eta_secs != null ? ` · quedan ${formatDuration(p.eta_secs)}` : "");
+
+        item.append(name, bar, detail);
+        return item;
+    }));
+}
+
 // ---- Helpers ----
+function errorMessage(err) {
+    if (typeof err === "string") return err;
+    return err && err.message;
+}
+
+// Show each error next to its input; fields without errors are cleared
+function showFieldErrors(view, fields) {
+    const byField = new Map((fields || []).map((f) => [f.field, f.message]));
+    view.querySelectorAll("[data-error-for]").forEach((el) => {
+        const message = byField.get(el.dataset.errorFor);
+        el.textContent = message || "";
+        el.classList.toggle("hidden", !message);
+    });
+}
+
+function formatBytes(bytes) {
+    if (bytes < 1024) return `${Math.round(bytes)} B`;
+    if (bytes < 1024 * 1024) return `${(bytes / 1024).toFixed(0)} KB`;
+    return `${(bytes / (1024 * 1024)).toFixed(1)} MB`;
+}
+
+function formatDuration(secs) {
+    if (secs < 60) return `${secs} s`;
+    const mins = Math.floor(secs / 60);
+    return `${mins} min ${secs % 60} s`;
+}
+
 function shortenPath(path) {
     if (!path) return "—";
     const home = path.replace(/^\/Users\/[^/]+/, "~");